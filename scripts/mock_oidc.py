@@ -0,0 +1,110 @@
+#!/usr/bin/env python3
+"""
+A tiny OpenID Connect provider for trying out single sign-on locally. It logs everyone straight in as the same
+user, without asking, and signs ID tokens with the client secret (HS256), so it only needs the standard library.
+
+    python3 scripts/mock_oidc.py [username] [port]
+
+Then run Commonplace with:
+
+    COMMONPLACE_OIDC_ISSUER=http://localhost:9000
+    COMMONPLACE_OIDC_CLIENT_ID=commonplace
+    COMMONPLACE_OIDC_CLIENT_SECRET=s3cret
+    COMMONPLACE_OIDC_REDIRECT_URL=http://localhost:8000/login/oidc/callback
+
+The token endpoint checks the client ID and secret, the redirect URL, and the PKCE code verifier, and the ID token
+includes the nonce from the login request, so a login only succeeds if Commonplace gets all of those right.
+"""
+
+import base64
+import hashlib
+import hmac
+import json
+import sys
+import time
+import urllib.parse
+from http.server import BaseHTTPRequestHandler, HTTPServer
+
+USERNAME = sys.argv[1] if len(sys.argv) > 1 else "carol"
+PORT = int(sys.argv[2]) if len(sys.argv) > 2 else 9000
+ISSUER = f"http://localhost:{PORT}"
+CLIENT_ID = "commonplace"
+CLIENT_SECRET = "s3cret"
+
+# Authorization codes that haven't been exchanged yet, along with the login request each was given out for.
+codes = {}
+
+
+def b64(data):
+    return base64.urlsafe_b64encode(data).rstrip(b"=").decode()
+
+
+def id_token(claims):
+    header = {"alg": "HS256", "typ": "JWT"}
+    signed = b64(json.dumps(header).encode()) + "." + b64(json.dumps(claims).encode())
+    signature = hmac.new(CLIENT_SECRET.encode(), signed.encode(), hashlib.sha256).digest()
+    return signed + "." + b64(signature)
+
+
+class Handler(BaseHTTPRequestHandler):
+    def reply(self, status, body="", headers={}):
+        self.send_response(status)
+        for name, value in headers.items():
+            self.send_header(name, value)
+        self.end_headers()
+        self.wfile.write(body.encode())
+
+    def error(self, description):
+        self.reply(400, json.dumps({"error": "invalid_grant", "error_description": description}))
+
+    def do_GET(self):
+        url = urllib.parse.urlparse(self.path)
+        query = dict(urllib.parse.parse_qsl(url.query))
+        if url.path == "/.well-known/openid-configuration":
+            self.reply(200, json.dumps({
+                "issuer": ISSUER,
+                "authorization_endpoint": ISSUER + "/authorize",
+                "token_endpoint": ISSUER + "/token",
+                "jwks_uri": ISSUER + "/jwks",
+            }))
+        elif url.path == "/jwks":
+            self.reply(200, json.dumps({"keys": []}))
+        elif url.path == "/authorize":
+            if query.get("client_id") != CLIENT_ID or query.get("code_challenge_method") != "S256":
+                return self.reply(400, "Unknown client, or not using PKCE")
+            code = b64(hashlib.sha256(str(time.time_ns()).encode()).digest())
+            codes[code] = query
+            params = urllib.parse.urlencode({"code": code, "state": query["state"]})
+            self.reply(302, headers={"Location": query["redirect_uri"] + "?" + params})
+        else:
+            self.reply(404)
+
+    def do_POST(self):
+        length = int(self.headers["Content-Length"])
+        form = dict(urllib.parse.parse_qsl(self.rfile.read(length).decode()))
+        login = codes.pop(form.get("code"), None)
+        if login is None:
+            return self.error("unknown code")
+        if form.get("client_id") != CLIENT_ID or form.get("client_secret") != CLIENT_SECRET:
+            return self.error("wrong client ID or secret")
+        if form.get("redirect_uri") != login["redirect_uri"]:
+            return self.error("redirect URL doesn't match the login request")
+        challenge = b64(hashlib.sha256(form.get("code_verifier", "").encode()).digest())
+        if challenge != login["code_challenge"]:
+            return self.error("code verifier doesn't match the code challenge")
+
+        now = int(time.time())
+        claims = {
+            "iss": ISSUER,
+            "aud": CLIENT_ID,
+            "sub": "mock-" + USERNAME,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": login["nonce"],
+            "preferred_username": USERNAME,
+        }
+        self.reply(200, json.dumps({"access_token": "unused", "token_type": "Bearer", "id_token": id_token(claims)}))
+
+
+print(f"Mock OpenID Connect provider at {ISSUER}, logging everyone in as {USERNAME}")
+HTTPServer(("localhost", PORT), Handler).serve_forever()
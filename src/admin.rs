@@ -0,0 +1,112 @@
+//! Commands for looking after the data from the command line, without going through a running server: checking the
+//! database for problems, and showing some statistics about it. Like the other commands, these need exclusive
+//! access to the database.
+
+use crate::{
+    auth::UserStore,
+    store::{Visibility, ZettelRecord, ZettelStore, ZETTELS_TREE},
+    workspace::WorkspaceStore,
+};
+use commonplace::ZettelId;
+use std::{collections::BTreeMap, convert::TryInto, ops::Deref};
+
+/// Look for problems in the database, and in the search index if it's kept on disk (`index` is what
+/// `index::count_on_disk` found). Returns a description of each problem found.
+pub fn check(store: &ZettelStore, index: Option<Result<u64, String>>) -> Vec<String> {
+    let mut problems = store.check();
+    let users = UserStore::new(store);
+    let workspaces = WorkspaceStore::new(store);
+
+    /*
+     * Zettels that can't be read have already been reported by the store, so are skipped here.
+     */
+    let zettels = readable_zettels(store);
+    for (id, zettel) in &zettels {
+        if let Some(owner) = &zettel.permissions.owner {
+            if users.get(owner).is_none() {
+                problems.push(format!("Zettel {} is owned by {}, who doesn't exist", id.0, owner));
+            }
+        }
+        if let Some(workspace) = &zettel.permissions.workspace {
+            if workspaces.get(workspace).is_none() {
+                problems.push(format!("Zettel {} is in workspace {}, which doesn't exist", id.0, workspace));
+            }
+        }
+    }
+
+    for entry in store.tree("workspaces").iter() {
+        let name = String::from_utf8_lossy(&entry.unwrap().0).into_owned();
+        for member in workspaces.get(&name).unwrap().members {
+            if users.get(&member).is_none() {
+                problems.push(format!("Workspace {} has member {}, who doesn't exist", name, member));
+            }
+        }
+    }
+
+    match index {
+        Some(Ok(indexed)) if indexed != zettels.len() as u64 => problems.push(format!(
+            "The search index has {} Zettels, but there are {} in the database",
+            indexed,
+            zettels.len()
+        )),
+        Some(Err(problem)) => problems.push(problem),
+        _ => {}
+    }
+
+    problems
+}
+
+/// Print some statistics about the database and the search index (if it's kept on disk, as for `check`).
+pub fn print_stats(store: &ZettelStore, index: Option<Result<u64, String>>) {
+    let zettels = readable_zettels(store);
+    let mut visibility = BTreeMap::<&str, usize>::new();
+    let mut tags = BTreeMap::<&str, usize>::new();
+    for (_, zettel) in &zettels {
+        let name = match (&zettel.permissions.owner, zettel.permissions.visibility) {
+            (None, _) => "unowned",
+            (Some(_), Visibility::Private) => "private",
+            (Some(_), Visibility::Workspace) => "workspace",
+            (Some(_), Visibility::Public) => "public",
+        };
+        *visibility.entry(name).or_default() += 1;
+        for tag in &zettel.tags {
+            *tags.entry(tag).or_default() += 1;
+        }
+    }
+
+    println!("Zettels: {}", zettels.len());
+    for (name, count) in &visibility {
+        println!("    {}: {}", name, count);
+    }
+    println!("Shared publicly: {}", zettels.iter().filter(|(_, zettel)| zettel.share_token.is_some()).count());
+    println!("Tags: {}", tags.len());
+    if let Some((tag, count)) = tags.iter().max_by_key(|(_, count)| **count) {
+        println!("    Most used: {} ({} Zettels)", tag, count);
+    }
+    println!("Encrypted: {}", if store.is_encrypted() { "yes" } else { "no" });
+    println!("Database size on disk: {} bytes", store.db().size_on_disk().unwrap());
+
+    match index {
+        Some(Ok(indexed)) => println!("Search index: {} Zettels", indexed),
+        Some(Err(problem)) => println!("Search index: {}", problem),
+        None => println!("Search index: kept in memory"),
+    }
+
+    println!("Entries in each tree:");
+    for (name, tree) in store.trees() {
+        println!("    {}: {}", name, tree.len());
+    }
+}
+
+/// Get every Zettel that can be read, skipping any that can't.
+fn readable_zettels(store: &ZettelStore) -> Vec<(ZettelId, ZettelRecord)> {
+    store
+        .tree(ZETTELS_TREE)
+        .iter()
+        .filter_map(|entry| {
+            let (key, value) = entry.unwrap();
+            let id = ZettelId::decode(key.deref().try_into().ok()?);
+            Some((id, store.decode(id, &value).ok()?))
+        })
+        .collect()
+}
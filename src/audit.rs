@@ -0,0 +1,138 @@
+//! An append-only log of every change made through the API, recording who made it, what they did, and which Zettel
+//! (if any) it was to. Entries are never changed or removed, and can only be read by admins.
+//!
+//! Entries are keyed by the time they were recorded followed by a unique ID, so they're stored in order and can be
+//! looked up by time range without reading the whole log.
+
+use crate::{auth::CurrentUser, store::ZettelStore, AppState};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use commonplace::ZettelId;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+const MAX_LIMIT: usize = 1000;
+
+pub struct AuditLog {
+    db: sled::Db,
+    entries: sled::Tree,
+    /// Held for reading while an entry is recorded, so entries aren't written while a snapshot is being taken (see
+    /// `ZettelStore::pause_writes`). Like changes to the store, this means `record` can block.
+    snapshot_lock: Arc<RwLock<()>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    CreateZettel,
+    UpdateZettel,
+    ImportZettel,
+    ShareZettel,
+    UnshareZettel,
+    SetPermissions,
+    RenameTag,
+    CreateUser,
+    CreateToken,
+    RevokeToken,
+    CreateWorkspace,
+    AddMember,
+    RemoveMember,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// The user who made the change.
+    pub username: String,
+    pub action: Action,
+    pub zettel: Option<ZettelId>,
+    /// Anything else worth knowing about the change, like the name of the tag that was renamed. This never includes
+    /// the content of Zettels, so it's safe to keep even when they're encrypted.
+    pub detail: Option<String>,
+}
+
+impl AuditLog {
+    pub fn new(store: &ZettelStore) -> AuditLog {
+        AuditLog { db: store.db(), entries: store.tree("audit_log"), snapshot_lock: store.snapshot_lock() }
+    }
+
+    pub fn record(&self, username: &str, action: Action, zettel: Option<ZettelId>, detail: Option<String>) {
+        let entry = AuditEntry { at: Utc::now(), username: username.to_string(), action, zettel, detail };
+
+        /*
+         * Two entries can be recorded in the same microsecond, so the time is followed by an ID that's unique to the
+         * database, to stop one from overwriting the other.
+         */
+        let mut key = Vec::from(time_key(entry.at));
+        key.extend(self.db.generate_id().unwrap().to_be_bytes());
+        let _writing = self.snapshot_lock.read().unwrap();
+        self.entries.insert(key, serde_cbor::to_vec(&entry).unwrap()).unwrap();
+    }
+
+    /// Get the entries recorded between `since` and `until` (inclusive) that match `filter`, newest first.
+    pub fn query(
+        &self,
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        filter: impl Fn(&AuditEntry) -> bool,
+        limit: usize,
+    ) -> Vec<AuditEntry> {
+        let start = since.map(time_key).unwrap_or([0; 8]);
+        /*
+         * The range's end is exclusive, so it's the microsecond after `until`. That can overflow for the very latest
+         * times, which are after everything anyway, so then there's no end.
+         */
+        let end = until
+            .and_then(|until| until.checked_add_signed(chrono::Duration::microseconds(1)))
+            .map(time_key)
+            .unwrap_or([0xff; 8]);
+
+        self.entries
+            .range(start..end)
+            .rev()
+            .map(|entry| serde_cbor::from_slice::<AuditEntry>(&entry.unwrap().1).unwrap())
+            .filter(filter)
+            .take(limit)
+            .collect()
+    }
+}
+
+/// Big-endian microseconds since the epoch, so keys sort in time order. Times before the epoch aren't recorded, so
+/// they're clamped to it.
+fn time_key(at: DateTime<Utc>) -> [u8; 8] {
+    (at.timestamp_micros().max(0) as u64).to_be_bytes()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AuditParams {
+    /// Only include changes made by this user.
+    user: Option<String>,
+    /// Only include changes to this Zettel.
+    zettel: Option<ZettelId>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// How many entries to return. Defaults to `100`, and can be at most `1000`.
+    limit: Option<usize>,
+}
+
+pub async fn query(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Query(params): Query<AuditParams>,
+) -> Result<Json<Vec<AuditEntry>>, StatusCode> {
+    if !user.is_admin() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let filter = |entry: &AuditEntry| {
+        params.user.as_ref().is_none_or(|user| entry.username == *user)
+            && params.zettel.is_none_or(|zettel| entry.zettel == Some(zettel))
+    };
+    let limit = params.limit.unwrap_or(100).min(MAX_LIMIT);
+    Ok(Json(state.audit.query(params.since, params.until, filter, limit)))
+}
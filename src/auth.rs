@@ -0,0 +1,524 @@
+//! User accounts and sessions. Users log in with a username and password, and get a session cookie that's used to
+//! authenticate their requests to the API. Passwords are hashed with Argon2, and session tokens are only stored as
+//! SHA-256 hashes, so someone who can read the database can't use it to log in.
+//!
+//! Scripts and integrations can instead use API tokens (see `tokens`), which are sent as a bearer token in the
+//! `Authorization` header, and can be limited to a scope.
+//!
+//! When there are no users yet, the first account can be created through `auth.setup`, and is made an admin.
+//! After that, only admins can create more accounts.
+//!
+//! Users can also log in through a single sign-on provider (see `oidc`), in which case their account is linked to
+//! their identity with the provider, and doesn't have a password.
+
+use crate::{
+    audit::Action,
+    html,
+    store::{random_token, ZettelStore},
+    AppState,
+};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+
+pub const SESSION_COOKIE: &str = "commonplace_session";
+
+/// How long a session lasts before the user has to log in again, in days.
+const SESSION_DAYS: i64 = 30;
+
+/// A hash of a password no one has, with the same parameters as real ones. Passwords for users that don't exist (or
+/// don't have a password) are checked against it, so that logging in takes as long as for a real user, and doesn't
+/// give away which usernames exist.
+const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$qoWMrmhzJ++xdDbtLGNJBg$2eYztYjxG79uunSoEoaAdatFjMryS9XiXF3YccjVP1k";
+
+pub struct UserStore {
+    /// Users, keyed by their username.
+    users: sled::Tree,
+    /// Sessions, keyed by the SHA-256 hash of their token.
+    sessions: sled::Tree,
+    /// API tokens, keyed by the SHA-256 hash of the token.
+    api_tokens: sled::Tree,
+    /// The users linked to single sign-on identities, keyed by the provider's issuer and the user's subject
+    /// identifier with the provider, separated by a NUL byte.
+    identities: sled::Tree,
+    /// Held while creating the first user, so two can't be created at once.
+    setup_lock: Mutex<()>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserRecord {
+    /// The user's password, hashed with Argon2, as a PHC string. This is empty for users that were created by
+    /// logging in with single sign-on, who can't log in with a password.
+    pub password_hash: String,
+    pub admin: bool,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct SessionRecord {
+    username: String,
+    expires: DateTime<Utc>,
+}
+
+/// What a request is allowed to do. Requests authenticated with a session can do anything the user can, while
+/// API tokens can be limited to a narrower scope.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Can only read Zettels.
+    Read,
+    /// Can read and change Zettels.
+    ReadWrite,
+    /// Can do anything the user can, including managing tokens and (if the user is an admin) other users.
+    Admin,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ApiTokenRecord {
+    /// An identifier for the token that isn't secret, so it can be listed and revoked.
+    pub id: String,
+    pub username: String,
+    /// A name for the token, to remind the user what it's for.
+    pub name: String,
+    pub scope: Scope,
+    pub created: DateTime<Utc>,
+}
+
+/// The user making a request. This is added to the request's extensions by `require_user`, so handlers behind it
+/// can get it with `Extension<CurrentUser>`.
+#[derive(Clone, Debug)]
+pub struct CurrentUser {
+    pub username: String,
+    pub admin: bool,
+    pub scope: Scope,
+}
+
+impl CurrentUser {
+    /// Whether this request can do things only admins can, like managing users. This needs both an admin user, and
+    /// a request with the `Admin` scope.
+    pub fn is_admin(&self) -> bool {
+        self.admin && self.scope == Scope::Admin
+    }
+}
+
+impl UserStore {
+    pub fn new(store: &ZettelStore) -> UserStore {
+        UserStore {
+            users: store.tree("users"),
+            sessions: store.tree("sessions"),
+            api_tokens: store.tree("api_tokens"),
+            identities: store.tree("oidc_identities"),
+            setup_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn get(&self, username: &str) -> Option<UserRecord> {
+        self.users.get(username.as_bytes()).unwrap().map(|bytes| serde_cbor::from_slice(&bytes).unwrap())
+    }
+
+    /// Create a new user. Returns `false` if there's already a user with that username.
+    pub fn create(&self, username: &str, password: &str, admin: bool) -> bool {
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string();
+        self.insert(username, password_hash, admin)
+    }
+
+    /// Create a user without a password, for someone logging in with single sign-on. If this is the first user,
+    /// they're made an admin. Returns `false` if there's already a user with that username.
+    pub fn create_without_password(&self, username: &str) -> bool {
+        let _setup = self.setup_lock.lock().unwrap();
+        let admin = self.is_empty();
+        self.insert(username, String::new(), admin)
+    }
+
+    fn insert(&self, username: &str, password_hash: String, admin: bool) -> bool {
+        let record = UserRecord { password_hash, admin, created: Utc::now() };
+        self.users
+            .compare_and_swap(username.as_bytes(), None::<&[u8]>, Some(serde_cbor::to_vec(&record).unwrap()))
+            .unwrap()
+            .is_ok()
+    }
+
+    /// Create the first user, as an admin. Returns `false` if there are already users.
+    pub fn create_first(&self, username: &str, password: &str) -> bool {
+        let _setup = self.setup_lock.lock().unwrap();
+        self.is_empty() && self.create(username, password, true)
+    }
+
+    /// Check a user's password, returning the user if it's correct.
+    pub fn verify(&self, username: &str, password: &str) -> Option<UserRecord> {
+        let user = self.get(username).filter(|user| !user.password_hash.is_empty());
+        let hash = match &user {
+            Some(user) => PasswordHash::new(&user.password_hash).unwrap(),
+            None => PasswordHash::new(DUMMY_PASSWORD_HASH).unwrap(),
+        };
+        let verified = Argon2::default().verify_password(password.as_bytes(), &hash).is_ok();
+        user.filter(|_| verified)
+    }
+
+    /// Start a new session for a user, returning its token.
+    pub fn start_session(&self, username: &str) -> String {
+        let token = random_token();
+        let session =
+            SessionRecord { username: username.to_string(), expires: Utc::now() + Duration::days(SESSION_DAYS) };
+        self.sessions.insert(hash_token(&token), serde_cbor::to_vec(&session).unwrap()).unwrap();
+        token
+    }
+
+    /// Find the user a session belongs to. Returns `None` if the session doesn't exist or has expired, or if the
+    /// user no longer exists.
+    pub fn session(&self, token: &str) -> Option<CurrentUser> {
+        let key = hash_token(token);
+        let session: SessionRecord = serde_cbor::from_slice(&self.sessions.get(key).unwrap()?).unwrap();
+        if session.expires < Utc::now() {
+            self.sessions.remove(key).unwrap();
+            return None;
+        }
+
+        let user = self.get(&session.username)?;
+        Some(CurrentUser { username: session.username, admin: user.admin, scope: Scope::Admin })
+    }
+
+    pub fn end_session(&self, token: &str) {
+        self.sessions.remove(hash_token(token)).unwrap();
+    }
+
+    /// Create a new API token for a user, returning the token itself along with its record. The token can't be
+    /// recovered after this, as only its hash is stored.
+    pub fn create_api_token(&self, username: &str, name: &str, scope: Scope) -> (String, ApiTokenRecord) {
+        let token = format!("cp_{}", random_token());
+        let record = ApiTokenRecord {
+            id: random_token()[..12].to_string(),
+            username: username.to_string(),
+            name: name.to_string(),
+            scope,
+            created: Utc::now(),
+        };
+        self.api_tokens.insert(hash_token(&token), serde_cbor::to_vec(&record).unwrap()).unwrap();
+        (token, record)
+    }
+
+    /// Get all of a user's API tokens.
+    pub fn api_tokens(&self, username: &str) -> Vec<ApiTokenRecord> {
+        self.api_tokens
+            .iter()
+            .values()
+            .map(|bytes| serde_cbor::from_slice::<ApiTokenRecord>(&bytes.unwrap()).unwrap())
+            .filter(|record| record.username == username)
+            .collect()
+    }
+
+    /// Revoke one of a user's API tokens by its ID. Returns `false` if the user doesn't have a token with that ID.
+    pub fn revoke_api_token(&self, username: &str, id: &str) -> bool {
+        for entry in self.api_tokens.iter() {
+            let (key, bytes) = entry.unwrap();
+            let record: ApiTokenRecord = serde_cbor::from_slice(&bytes).unwrap();
+            if record.username == username && record.id == id {
+                self.api_tokens.remove(key).unwrap();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Find the user an API token belongs to. Returns `None` if the token doesn't exist, or if the user no longer
+    /// exists.
+    pub fn api_token(&self, token: &str) -> Option<CurrentUser> {
+        let bytes = self.api_tokens.get(hash_token(token)).unwrap()?;
+        let record: ApiTokenRecord = serde_cbor::from_slice(&bytes).unwrap();
+        let user = self.get(&record.username)?;
+        Some(CurrentUser { username: record.username, admin: user.admin, scope: record.scope })
+    }
+
+    /// Find the user linked to a single sign-on identity.
+    pub fn identity(&self, issuer: &str, subject: &str) -> Option<String> {
+        let bytes = self.identities.get(identity_key(issuer, subject)).unwrap()?;
+        Some(String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    pub fn link_identity(&self, issuer: &str, subject: &str, username: &str) {
+        self.identities.insert(identity_key(issuer, subject), username.as_bytes()).unwrap();
+    }
+}
+
+fn identity_key(issuer: &str, subject: &str) -> Vec<u8> {
+    [issuer.as_bytes(), &[0], subject.as_bytes()].concat()
+}
+
+pub fn hash_token(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Get the session token from a request's cookies, if it has one.
+fn session_token(headers: &HeaderMap) -> Option<String> {
+    cookie(headers, SESSION_COOKIE)
+}
+
+pub fn cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            let (found, value) = cookie.trim().split_once('=')?;
+            (found == name).then(|| value.to_string())
+        })
+}
+
+/*
+ * The cookie isn't marked `Secure`, as Commonplace is often served over plain HTTP on a local network. When it's
+ * served over HTTPS, the proxy in front of it should add the flag.
+ */
+pub fn session_cookie(token: &str) -> String {
+    format!(
+        "{}={}; HttpOnly; SameSite=Lax; Path=/; Max-Age={}",
+        SESSION_COOKIE,
+        token,
+        Duration::days(SESSION_DAYS).num_seconds()
+    )
+}
+
+fn clear_session_cookie() -> String {
+    format!("{}=; HttpOnly; SameSite=Lax; Path=/; Max-Age=0", SESSION_COOKIE)
+}
+
+/// Get the API token from a request's `Authorization` header, if it has one.
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+}
+
+/// Middleware that rejects requests without a valid session or API token, and makes the user (and their `Access`)
+/// available to handlers. Requests with a read-only token are only allowed to make `GET` requests.
+pub async fn require_user(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let user = match bearer_token(request.headers()) {
+        Some(token) => state.users.api_token(&token),
+        None => session_token(request.headers()).and_then(|token| state.users.session(&token)),
+    }
+    .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if user.scope == Scope::Read && request.method() != Method::GET {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    request.extensions_mut().insert(state.workspaces.access(&user));
+    request.extensions_mut().insert(user);
+    Ok(next.run(request).await)
+}
+
+/// Middleware for the frontend's pages, which sends people without a valid session to the login page.
+pub async fn redirect_to_login(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    match session_token(request.headers()).and_then(|token| state.users.session(&token)) {
+        Some(_) => next.run(request).await,
+        None => Redirect::to("/login").into_response(),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Me {
+    pub username: String,
+    pub admin: bool,
+    /// What the current request is allowed to do. This is only included for the current user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Scope>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuthStatus {
+    /// Whether there are no users yet, so the first one needs to be created with `auth.setup`.
+    pub setup_required: bool,
+    /// Whether users can log in with single sign-on, at `/login/oidc`.
+    pub single_sign_on: bool,
+}
+
+pub async fn status(State(state): State<Arc<AppState>>) -> Json<AuthStatus> {
+    Json(AuthStatus { setup_required: state.users.is_empty(), single_sign_on: state.oidc.is_some() })
+}
+
+pub async fn setup(
+    State(state): State<Arc<AppState>>,
+    Json(credentials): Json<Credentials>,
+) -> Result<impl IntoResponse, StatusCode> {
+    validate(&credentials.username, &credentials.password)?;
+
+    let users = state.clone();
+    let Credentials { username, password } = credentials;
+    let (username, created) = tokio::task::spawn_blocking(move || {
+        let created = users.users.create_first(&username, &password);
+        if created {
+            users.audit.record(&username, Action::CreateUser, None, Some(format!("{} (first user)", username)));
+        }
+        (username, created)
+    })
+    .await
+    .unwrap();
+    if !created {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let token = state.users.start_session(&username);
+    Ok((
+        [(header::SET_COOKIE, session_cookie(&token))],
+        Json(Me { username, admin: true, scope: Some(Scope::Admin) }),
+    ))
+}
+
+pub async fn login(
+    State(state): State<Arc<AppState>>,
+    Json(credentials): Json<Credentials>,
+) -> Result<impl IntoResponse, StatusCode> {
+    /*
+     * Checking a password is deliberately slow, so we do it on a blocking thread rather than holding up the
+     * runtime.
+     */
+    let users = state.clone();
+    let Credentials { username, password } = credentials;
+    let (username, user) = tokio::task::spawn_blocking(move || {
+        let user = users.users.verify(&username, &password);
+        (username, user)
+    })
+    .await
+    .unwrap();
+    let user = user.ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let token = state.users.start_session(&username);
+    Ok((
+        [(header::SET_COOKIE, session_cookie(&token))],
+        Json(Me { username, admin: user.admin, scope: Some(Scope::Admin) }),
+    ))
+}
+
+pub async fn logout(State(state): State<Arc<AppState>>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(token) = session_token(&headers) {
+        state.users.end_session(&token);
+    }
+    [(header::SET_COOKIE, clear_session_cookie())]
+}
+
+pub async fn me(Extension(user): Extension<CurrentUser>) -> Json<Me> {
+    Json(Me { username: user.username, admin: user.admin, scope: Some(user.scope) })
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+pub async fn create_user(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Json(new_user): Json<NewUser>,
+) -> Result<Json<Me>, StatusCode> {
+    if !user.is_admin() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    validate(&new_user.username, &new_user.password)?;
+
+    let users = state.clone();
+    let NewUser { username, password, admin } = new_user;
+    let (username, created) = tokio::task::spawn_blocking(move || {
+        let created = users.users.create(&username, &password, admin);
+        if created {
+            users.audit.record(&user.username, Action::CreateUser, None, Some(username.clone()));
+        }
+        (username, created)
+    })
+    .await
+    .unwrap();
+
+    if created {
+        Ok(Json(Me { username, admin, scope: None }))
+    } else {
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+/// Usernames end up in URLs and logs, so we keep them simple. Passwords just need to be long enough.
+fn validate(username: &str, password: &str) -> Result<(), StatusCode> {
+    if is_valid_username(username) && password.chars().count() >= 8 {
+        Ok(())
+    } else {
+        Err(StatusCode::BAD_REQUEST)
+    }
+}
+
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= 64
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// A simple login page, which is shown instead of the frontend to people who aren't logged in. If there aren't any
+/// users yet, it creates the first one instead.
+pub async fn login_page() -> Html<String> {
+    Html(html::page("Log in to Commonplace", LOGIN_FORM))
+}
+
+const LOGIN_FORM: &str = r#"<form id="login">
+<p><label>Username <input name="username" autocomplete="username" required></label></p>
+<p><label>Password <input name="password" type="password" autocomplete="current-password" required></label></p>
+<p id="setup" hidden>There aren't any users yet, so this will create an admin account.</p>
+<p><button type="submit">Log in</button> <span id="error"></span></p>
+</form>
+<p id="sso" hidden><a href="/login/oidc">Log in with single sign-on</a></p>
+<script>
+const form = document.getElementById("login");
+let endpoint = "/api/auth.login";
+fetch("/api/auth.status").then((response) => response.json()).then((status) => {
+  document.getElementById("sso").hidden = !status.single_sign_on;
+  if (status.setup_required) {
+    endpoint = "/api/auth.setup";
+    document.getElementById("setup").hidden = false;
+    form.querySelector("button").textContent = "Create account";
+  }
+});
+form.addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const response = await fetch(endpoint, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ username: form.username.value, password: form.password.value }),
+  });
+  if (response.ok) {
+    window.location.href = "/";
+  } else {
+    document.getElementById("error").textContent = response.status === 400
+      ? "Usernames can only contain letters, numbers, '-', '_', and '.', and passwords need at least 8 characters."
+      : "Incorrect username or password.";
+  }
+});
+</script>
+"#;
@@ -0,0 +1,215 @@
+//! Lossless backups of the whole database as newline-delimited JSON. We don't want to rely on sled's on-disk
+//! format for long-term backups, so every tree is dumped: Zettels are written as JSON, so they can be read by
+//! anything, and entries in any other tree are written as hex-encoded bytes.
+//!
+//! The first line of a backup is a header, describing the backup's format and the format of the Zettels in it.
+//! Every line after that is a single entry from a tree.
+//!
+//! If the database is encrypted (see `encryption`), the Zettels in its backups are too: they're written as raw
+//! entries, and the header includes the parameters needed to get their key from the passphrase or key file.
+
+use crate::{
+    encryption::{KeyParams, META_TREE},
+    store::{ZettelRecord, ZettelStore, CURRENT_ZETTEL_FORMAT_VERSION, ZETTELS_TREE},
+};
+use commonplace::ZettelId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    io::{self, BufRead, Write},
+    ops::Deref,
+};
+
+/// The version of the backup format. This should be incremented whenever the format changes in a way that would
+/// stop an older version from restoring it correctly.
+const BACKUP_VERSION: u32 = 2;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub backup_version: u32,
+    pub zettel_format_version: u16,
+    pub created: chrono::DateTime<chrono::Utc>,
+    /// If the Zettels in the backup are encrypted, the parameters of the key they're encrypted with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<KeyParams>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Entry {
+    Zettel { tree: String, id: ZettelId, record: ZettelRecord },
+    Raw { tree: String, key: String, value: String },
+}
+
+/// How many entries were backed up or restored from each tree.
+pub type Counts = BTreeMap<String, usize>;
+
+/// Write a backup of everything in `store` to `writer`. Changes to the store are paused while this happens, so the
+/// backup is consistent.
+pub fn backup<W: Write>(store: &ZettelStore, mut writer: W) -> io::Result<Counts> {
+    let header = Header {
+        backup_version: BACKUP_VERSION,
+        zettel_format_version: CURRENT_ZETTEL_FORMAT_VERSION,
+        created: chrono::Utc::now(),
+        encryption: store.key_params().cloned(),
+    };
+    serde_json::to_writer(&mut writer, &header)?;
+    writer.write_all(b"\n")?;
+
+    let _paused = store.pause_writes();
+    let mut counts = Counts::new();
+    for (name, tree) in store.trees() {
+        /*
+         * The encryption parameters belong to the database, not the data in it - a backup restored into another
+         * database gets that database's key, and the backup's parameters are in its header.
+         */
+        if name == META_TREE {
+            continue;
+        }
+
+        let mut count = 0;
+        for entry in tree.iter() {
+            let (key, value) = entry.map_err(io::Error::other)?;
+
+            /*
+             * Zettels are decoded, so the backup can be read without knowing how they're serialized. This also
+             * makes sure that every Zettel is in the format the header says it is. Encrypted Zettels are still
+             * decoded to check them, but are written as they are.
+             */
+            let entry = if name == ZETTELS_TREE {
+                let id = ZettelId::decode(key.deref().try_into().map_err(|_| invalid_data("Invalid Zettel ID"))?);
+                let record = store
+                    .decode(id, &value)
+                    .map_err(|err| invalid_data(&format!("Can't read Zettel {}: {:?}", id.0, err)))?;
+                if store.is_encrypted() {
+                    Entry::Raw { tree: name.clone(), key: to_hex(&key), value: to_hex(&value) }
+                } else {
+                    Entry::Zettel { tree: name.clone(), id, record }
+                }
+            } else {
+                Entry::Raw { tree: name.clone(), key: to_hex(&key), value: to_hex(&value) }
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+
+        if count > 0 {
+            counts.insert(name, count);
+        }
+    }
+
+    writer.flush()?;
+    Ok(counts)
+}
+
+/// Restore a backup into `store`, which must be empty. Every Zettel is checked to make sure it's in the current
+/// format and can be read back before anything is written, so a bad backup won't leave a half-restored database.
+///
+/// Zettels are written in the store's format rather than the backup's, so restoring an unencrypted backup into an
+/// encrypted store encrypts them, and restoring an encrypted backup re-encrypts them with the store's key.
+pub fn restore<R: BufRead>(store: &ZettelStore, reader: R) -> io::Result<Counts> {
+    if store.trees().iter().any(|(name, tree)| name != META_TREE && !tree.is_empty()) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Can only restore into an empty database"));
+    }
+
+    let mut lines = reader.lines();
+    let header = lines.next().ok_or_else(|| invalid_data("Backup is empty"))??;
+    let header: Header = serde_json::from_str(&header)?;
+    if !(1..=BACKUP_VERSION).contains(&header.backup_version) {
+        return Err(invalid_data(&format!("Unsupported backup version {}", header.backup_version)));
+    }
+    if header.zettel_format_version != CURRENT_ZETTEL_FORMAT_VERSION {
+        return Err(invalid_data(&format!(
+            "Backup contains Zettels in format version {}, but only version {} is supported",
+            header.zettel_format_version, CURRENT_ZETTEL_FORMAT_VERSION
+        )));
+    }
+
+    let cipher = match &header.encryption {
+        Some(params) => Some(store.cipher_for(params).ok_or_else(|| {
+            invalid_data(
+                "Backup is encrypted, so COMMONPLACE_ENCRYPTION_PASSPHRASE or COMMONPLACE_ENCRYPTION_KEY_FILE needs \
+                 to be set to the passphrase or key file it was made with",
+            )
+        })?),
+        None => None,
+    };
+
+    let mut entries = Vec::new();
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = serde_json::from_str::<Entry>(&line)
+            .map_err(|err| invalid_data(&format!("Invalid entry on line {}: {}", number + 2, err)))?;
+        /*
+         * Zettels are normally written as Zettel entries, and everything else as raw entries. The exception is
+         * encrypted Zettels, which are raw entries that get decrypted here.
+         */
+        let is_zettel = matches!(entry, Entry::Zettel { .. });
+        let (tree, key, value) = match entry {
+            Entry::Zettel { tree, .. } | Entry::Raw { tree, .. }
+                if (tree == ZETTELS_TREE && cipher.is_none()) != is_zettel =>
+            {
+                return Err(invalid_data(&format!("Unexpected entry for tree {} on line {}", tree, number + 2)));
+            }
+            Entry::Zettel { tree, id, record } => {
+                let bytes = store.encode(id, &record);
+                store
+                    .decode(id, &bytes)
+                    .map_err(|err| invalid_data(&format!("Can't restore Zettel {}: {:?}", id.0, err)))?;
+                (tree, id.encode().to_vec(), bytes)
+            }
+            Entry::Raw { tree, key, value } => {
+                let key =
+                    from_hex(&key).ok_or_else(|| invalid_data(&format!("Invalid key on line {}", number + 2)))?;
+                let value = from_hex(&value)
+                    .ok_or_else(|| invalid_data(&format!("Invalid value on line {}", number + 2)))?;
+                match &cipher {
+                    Some(cipher) if tree == ZETTELS_TREE => {
+                        let id =
+                            ZettelId::decode(key.deref().try_into().map_err(|_| {
+                                invalid_data(&format!("Invalid Zettel ID on line {}", number + 2))
+                            })?);
+                        let record = ZettelRecord::deserialize(id, &value, Some(cipher)).map_err(|err| {
+                            invalid_data(&format!("Can't restore Zettel on line {}: {:?}", number + 2, err))
+                        })?;
+                        (tree, key, store.encode(id, &record))
+                    }
+                    _ => (tree, key, value),
+                }
+            }
+        };
+        entries.push((tree, key, value));
+    }
+
+    let mut trees = BTreeMap::new();
+    let mut counts = Counts::new();
+    for (name, key, value) in entries {
+        let tree = trees.entry(name.clone()).or_insert_with(|| store.tree(&name));
+        tree.insert(key, value).map_err(io::Error::other)?;
+        *counts.entry(name).or_default() += 1;
+    }
+
+    store.flush();
+    Ok(counts)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
@@ -0,0 +1,127 @@
+//! Where the server keeps its data and how it runs, so several instances can run on one machine with their data
+//! mounted wherever is convenient. Each setting can come from (in order of precedence) a command-line flag, an
+//! environment variable, or a TOML config file, and otherwise has a default:
+//!
+//! | Flag                  | Environment variable            | Config file         | Default          |
+//! |-----------------------|---------------------------------|---------------------|------------------|
+//! | `--bind`              | `COMMONPLACE_BIND`              | `bind`              | `0.0.0.0:8000`   |
+//! | `--db`                | `COMMONPLACE_DB`                | `db`                | `db`             |
+//! | `--index`             | `COMMONPLACE_INDEX`             | `index`             | `index/`         |
+//! | `--snapshot-dir`      | `COMMONPLACE_SNAPSHOT_DIR`      | `snapshot-dir`      | `<db>-snapshots` |
+//! | `--commit-interval`   | `COMMONPLACE_COMMIT_INTERVAL`   | `commit-interval`   | `60` (seconds)   |
+//! | `--writer-heap-bytes` | `COMMONPLACE_WRITER_HEAP_BYTES` | `writer-heap-bytes` | 50MB             |
+//! | `--dist-dir`          | `COMMONPLACE_DIST_DIR`          | `dist-dir`          | `app/dist/`      |
+//!
+//! The config file is read from `--config` (or `COMMONPLACE_CONFIG`) if it's given, and otherwise from
+//! `commonplace.toml` if there is one. Relative paths are relative to the working directory, not the config file.
+//! The other features (how often snapshots are taken, single sign-on, encryption, and limits) are still configured
+//! through their own environment variables.
+
+use clap::Args;
+use serde::Deserialize;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "commonplace.toml";
+
+/*
+ * The frontend's location used to only be settable when building, so that's still respected as the default.
+ */
+const DEFAULT_DIST_DIR: &str = match option_env!("COMMONPLACE_DIST_DIR") {
+    Some(dir) => dir,
+    None => "app/dist/",
+};
+
+/// The command-line flags (and their environment variables) that override the config file.
+#[derive(Clone, Debug, Default, Args)]
+pub struct ConfigArgs {
+    /// The TOML config file to read [default: commonplace.toml, if it exists]
+    #[arg(long, env = "COMMONPLACE_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// The address to listen on [default: 0.0.0.0:8000]
+    #[arg(long, env = "COMMONPLACE_BIND", global = true)]
+    bind: Option<SocketAddr>,
+    /// The directory the database is kept in [default: db]
+    #[arg(long, env = "COMMONPLACE_DB", global = true)]
+    db: Option<PathBuf>,
+    /// The directory the search index is kept in [default: index/]
+    #[arg(long, env = "COMMONPLACE_INDEX", global = true)]
+    index: Option<PathBuf>,
+    /// The directory snapshots of the database are written to [default: <db>-snapshots]
+    #[arg(long, env = "COMMONPLACE_SNAPSHOT_DIR", global = true)]
+    snapshot_dir: Option<PathBuf>,
+    /// How often changes to the search index are committed, in seconds [default: 60]
+    #[arg(long, env = "COMMONPLACE_COMMIT_INTERVAL", global = true)]
+    commit_interval: Option<u64>,
+    /// How much memory the search index can use while adding Zettels, in bytes [default: 50000000]
+    #[arg(long, env = "COMMONPLACE_WRITER_HEAP_BYTES", global = true)]
+    writer_heap_bytes: Option<usize>,
+    /// The directory the built frontend is served from [default: app/dist/]
+    #[arg(long, env = "COMMONPLACE_DIST_DIR", global = true)]
+    dist_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    bind: Option<SocketAddr>,
+    db: Option<PathBuf>,
+    index: Option<PathBuf>,
+    snapshot_dir: Option<PathBuf>,
+    commit_interval: Option<u64>,
+    writer_heap_bytes: Option<usize>,
+    dist_dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub db: PathBuf,
+    pub index: PathBuf,
+    pub snapshot_dir: PathBuf,
+    pub commit_interval: Duration,
+    pub writer_heap_bytes: usize,
+    pub dist_dir: PathBuf,
+}
+
+impl Config {
+    pub fn load(args: ConfigArgs) -> Config {
+        let file = match &args.config {
+            Some(path) => read_file(path),
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => read_file(Path::new(DEFAULT_CONFIG_PATH)),
+            None => ConfigFile::default(),
+        };
+
+        let db = args.db.or(file.db).unwrap_or_else(|| PathBuf::from("db"));
+
+        /*
+         * Snapshots go next to the database by default, and are named after it, so instances with their databases
+         * in different places (even in the same directory) never write to each other's snapshots.
+         */
+        let snapshot_dir = args.snapshot_dir.or(file.snapshot_dir).unwrap_or_else(|| {
+            let name = db.file_name().unwrap_or("db".as_ref()).to_string_lossy();
+            db.with_file_name(format!("{}-snapshots", name))
+        });
+
+        Config {
+            bind: args.bind.or(file.bind).unwrap_or_else(|| "0.0.0.0:8000".parse().unwrap()),
+            index: args.index.or(file.index).unwrap_or_else(|| PathBuf::from("index/")),
+            db,
+            snapshot_dir,
+            commit_interval: Duration::from_secs(
+                args.commit_interval.or(file.commit_interval).unwrap_or(60).max(1),
+            ),
+            writer_heap_bytes: args.writer_heap_bytes.or(file.writer_heap_bytes).unwrap_or(50_000_000),
+            dist_dir: args.dist_dir.or(file.dist_dir).unwrap_or_else(|| PathBuf::from(DEFAULT_DIST_DIR)),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> ConfigFile {
+    let contents = std::fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("Can't read config file {}: {}", path.display(), err));
+    toml::from_str(&contents).unwrap_or_else(|err| panic!("Invalid config file {}: {}", path.display(), err))
+}
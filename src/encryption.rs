@@ -0,0 +1,115 @@
+//! Optional encryption at rest for Zettels, so that someone with a copy of the database (or a backup of it) can't
+//! read them. Records are encrypted with XChaCha20-Poly1305, using a key derived with Argon2 from a passphrase or
+//! the contents of a key file. The salt is stored in the database, along with a value encrypted with the key so
+//! we can tell if we've been given the wrong passphrase.
+//!
+//! Only the contents of the Zettels tree are encrypted. Tag names (which are used as keys in the tag index) and
+//! share tokens are still stored in plain text, and while encryption is on the search index is only kept in memory.
+//!
+//! This is configured through environment variables, and is turned off unless one of them is set:
+//!    - `COMMONPLACE_ENCRYPTION_PASSPHRASE` - the passphrase to derive the key from
+//!    - `COMMONPLACE_ENCRYPTION_KEY_FILE` - a file whose contents the key is derived from instead
+
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    AeadCore,
+    KeyInit,
+    XChaCha20Poly1305,
+    XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// The tree that the encryption parameters are stored in.
+pub const META_TREE: &str = "meta";
+pub const KEY_PARAMS_KEY: &[u8] = b"encryption";
+
+const NONCE_LENGTH: usize = 24;
+const CHECK_PLAINTEXT: &[u8] = b"commonplace";
+const CHECK_AAD: &[u8] = b"check";
+
+#[derive(Clone)]
+pub struct EncryptionConfig {
+    /// The passphrase or key file contents that the key is derived from.
+    secret: Vec<u8>,
+}
+
+impl EncryptionConfig {
+    pub fn from_env() -> Option<EncryptionConfig> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        match (var("COMMONPLACE_ENCRYPTION_PASSPHRASE"), var("COMMONPLACE_ENCRYPTION_KEY_FILE")) {
+            (Some(_), Some(_)) => {
+                panic!(
+                    "Only one of COMMONPLACE_ENCRYPTION_PASSPHRASE and COMMONPLACE_ENCRYPTION_KEY_FILE can be set"
+                )
+            }
+            (Some(passphrase), None) => Some(EncryptionConfig { secret: passphrase.into_bytes() }),
+            (None, Some(path)) => {
+                let secret =
+                    std::fs::read(&path).unwrap_or_else(|err| panic!("Can't read key file {}: {}", path, err));
+                Some(EncryptionConfig { secret })
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+/// What's needed, along with the passphrase or key file, to get the key that a database or backup was encrypted
+/// with. The values are base64-encoded, so they're readable in a backup's header.
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct KeyParams {
+    pub salt: String,
+    /// A known value encrypted with the key, to check that a key derived from these parameters is the right one.
+    pub check: String,
+}
+
+pub struct Cipher {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derive a new key from the configured secret, with a fresh salt.
+    pub fn generate(config: &EncryptionConfig) -> (Cipher, KeyParams) {
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = Cipher::with_salt(config, &salt);
+        let check = cipher.encrypt(CHECK_AAD, CHECK_PLAINTEXT);
+        (cipher, KeyParams { salt: STANDARD.encode(salt), check: STANDARD.encode(check) })
+    }
+
+    /// Derive the key described by `params` from the configured secret. Returns `None` if the secret isn't the one
+    /// it was originally derived from.
+    pub fn derive(config: &EncryptionConfig, params: &KeyParams) -> Option<Cipher> {
+        let salt = STANDARD.decode(&params.salt).ok()?;
+        let check = STANDARD.decode(&params.check).ok()?;
+        let cipher = Cipher::with_salt(config, &salt);
+        (cipher.decrypt(CHECK_AAD, &check)? == CHECK_PLAINTEXT).then_some(cipher)
+    }
+
+    fn with_salt(config: &EncryptionConfig, salt: &[u8]) -> Cipher {
+        let mut key = [0; 32];
+        Argon2::default().hash_password_into(&config.secret, salt, &mut key).unwrap();
+        Cipher { cipher: XChaCha20Poly1305::new(&key.into()) }
+    }
+
+    /// Encrypt `plaintext`, returning the nonce followed by the ciphertext. The `aad` isn't encrypted, but the
+    /// ciphertext can only be decrypted with the same `aad`, so it can be used to tie the ciphertext to a header.
+    pub fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut bytes = nonce.to_vec();
+        bytes.extend(self.cipher.encrypt(&nonce, Payload { msg: plaintext, aad }).unwrap());
+        bytes
+    }
+
+    /// Decrypt something encrypted with `encrypt`. Returns `None` if it was encrypted with a different key, or has
+    /// been tampered with.
+    pub fn decrypt(&self, aad: &[u8], bytes: &[u8]) -> Option<Vec<u8>> {
+        if bytes.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LENGTH);
+        self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad }).ok()
+    }
+}
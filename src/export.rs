@@ -0,0 +1,175 @@
+//! Exporting every Zettel as a zip archive of Markdown files, along with a JSON manifest describing them. This is
+//! meant to be readable without Commonplace, so links between Zettels are rewritten into normal Markdown links
+//! between the files.
+
+use crate::{markdown, store::ZettelRecord, workspace::Access, AppState};
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::header,
+    response::IntoResponse,
+    Extension,
+};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use commonplace::ZettelId;
+use futures_util::stream;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufWriter, Write},
+    sync::Arc,
+};
+use tokio::sync::mpsc;
+use tracing::error;
+use zip::{result::ZipResult, write::SimpleFileOptions, ZipWriter};
+
+/// The version of the manifest format. This should be incremented whenever the format changes in a way that would
+/// break something reading it.
+const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub zettels: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ManifestEntry {
+    pub id: ZettelId,
+    pub title: String,
+    /// The path of the Zettel's Markdown file within the archive.
+    pub path: String,
+    pub created: Option<DateTime<Utc>>,
+    pub tags: BTreeSet<String>,
+    pub links: BTreeSet<ZettelId>,
+}
+
+/// Write an archive of the given Zettels to `writer`. This doesn't need to seek, so can be used to stream the
+/// archive as it's produced.
+pub fn write_archive<W: Write>(zettels: &[(ZettelId, ZettelRecord)], writer: W) -> ZipResult<W> {
+    let paths =
+        zettels.iter().map(|(id, record)| (*id, file_name(*id, &record.title))).collect::<BTreeMap<_, _>>();
+    let titles = zettels.iter().map(|(id, record)| (*id, record.title.as_str())).collect::<BTreeMap<_, _>>();
+    let link = |target: ZettelId| match titles.get(&target) {
+        Some(title) => {
+            let text = if title.is_empty() { target.0.to_string() } else { title.to_string() };
+            format!("[{}]({})", text.replace('[', "\\[").replace(']', "\\]"), paths[&target].replace(' ', "%20"))
+        }
+        None => markdown::wiki_link(target, None),
+    };
+
+    let mut zip = ZipWriter::new_stream(writer);
+    let options = SimpleFileOptions::default();
+    let mut manifest = Manifest { version: MANIFEST_VERSION, exported_at: Utc::now(), zettels: Vec::new() };
+
+    for (id, record) in zettels {
+        /*
+         * Zip timestamps only have a resolution of two seconds, and can't go before 1980, so this is a best effort.
+         */
+        let modified = id.datetime().and_then(|created| {
+            zip::DateTime::from_date_and_time(
+                created.year() as u16,
+                created.month() as u8,
+                created.day() as u8,
+                created.hour() as u8,
+                created.minute() as u8,
+                (created.second() as u8).min(58),
+            )
+            .ok()
+        });
+        let file_options = match modified {
+            Some(modified) => options.last_modified_time(modified),
+            None => options,
+        };
+        zip.start_file(paths[id].as_str(), file_options)?;
+        zip.write_all(markdown::render(&record.title, &record.content, &link).as_bytes())?;
+
+        manifest.zettels.push(ManifestEntry {
+            id: *id,
+            title: record.title.clone(),
+            path: paths[id].clone(),
+            created: id.datetime(),
+            tags: record.tags.clone(),
+            links: record.content.links().into_iter().collect(),
+        });
+    }
+
+    zip.start_file("manifest.json", options)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest).map_err(io::Error::from)?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Pick a file name for a Zettel. This starts with the ID, so it's always unique, and then has as much of the title
+/// as can safely be put in a file name, so the files are easy to navigate.
+fn file_name(id: ZettelId, title: &str) -> String {
+    let title = title
+        .chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.' | ',' | '\'') { c } else { '_' })
+        .take(80)
+        .collect::<String>();
+    let title = title.trim_matches(|c: char| c == '.' || c.is_whitespace());
+
+    if title.is_empty() {
+        format!("{}.md", id.0)
+    } else {
+        format!("{} {}.md", id.0, title)
+    }
+}
+
+/// Export every Zettel the user can see.
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+) -> impl IntoResponse {
+    /*
+     * Archives can get big, so rather than building the whole thing in memory, we write it out from a blocking task
+     * and stream the chunks out as the response body.
+     */
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::task::spawn_blocking(move || {
+        let zettels = state.store.visible(&access);
+        let result =
+            write_archive(&zettels, BufWriter::new(ChannelWriter(sender.clone()))).and_then(|mut writer| {
+                writer.flush()?;
+                Ok(())
+            });
+
+        /*
+         * Send the error down the channel too, so the response is cut off rather than ending normally - otherwise
+         * the client would think it got the whole archive. If the client has gone away, there's no one to tell.
+         */
+        if let Err(err) = result {
+            error!("Failed to export archive: {:?}", err);
+            let _ = sender.blocking_send(Err(err.into()));
+        }
+    });
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((chunk, receiver))
+    });
+
+    let file_name = format!("attachment; filename=\"commonplace-{}.zip\"", Utc::now().format("%Y-%m-%d"));
+    (
+        [(header::CONTENT_TYPE, "application/zip".to_string()), (header::CONTENT_DISPOSITION, file_name)],
+        Body::from_stream(stream),
+    )
+}
+
+/// Adapts a channel into a `Write`r, so that the archive can be written from a blocking task while being streamed
+/// from an async one.
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+use crate::{store::ZettelRecord, workspace::Access, AppState};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension,
+    Json,
+};
+use commonplace::ZettelId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
+};
+
+/// The network of links between Zettels. This is built from the contents of the store when it's needed, rather
+/// than being maintained alongside it.
+pub struct Graph {
+    pub titles: BTreeMap<ZettelId, String>,
+    /// The outgoing links from each Zettel. These can include links to Zettels that don't exist (any more).
+    pub links: BTreeMap<ZettelId, BTreeSet<ZettelId>>,
+    /// The incoming links to each Zettel, only including links from Zettels that exist.
+    pub backlinks: BTreeMap<ZettelId, BTreeSet<ZettelId>>,
+}
+
+impl Graph {
+    pub fn build(zettels: &[(ZettelId, ZettelRecord)]) -> Graph {
+        let mut titles = BTreeMap::new();
+        let mut links = BTreeMap::new();
+        let mut backlinks: BTreeMap<ZettelId, BTreeSet<ZettelId>> = BTreeMap::new();
+
+        for (id, record) in zettels {
+            titles.insert(*id, record.title.clone());
+
+            let targets = record.content.links().into_iter().collect::<BTreeSet<_>>();
+            for target in &targets {
+                backlinks.entry(*target).or_default().insert(*id);
+            }
+            links.insert(*id, targets);
+        }
+
+        Graph { titles, links, backlinks }
+    }
+
+    pub fn contains(&self, id: ZettelId) -> bool {
+        self.titles.contains_key(&id)
+    }
+
+    /// Iterate over every link between two Zettels that exist, as `(source, target)` pairs.
+    pub fn edges(&self) -> impl Iterator<Item = (ZettelId, ZettelId)> + '_ {
+        self.links.iter().flat_map(move |(source, targets)| {
+            targets.iter().filter(|target| self.contains(**target)).map(move |target| (*source, *target))
+        })
+    }
+
+    /// The Zettels that exist and are linked to or from the given Zettel, ignoring the direction of the links.
+    pub fn neighbours(&self, id: ZettelId) -> BTreeSet<ZettelId> {
+        let outgoing = self.links.get(&id).into_iter().flatten();
+        let incoming = self.backlinks.get(&id).into_iter().flatten();
+        outgoing.chain(incoming).copied().filter(|neighbour| self.contains(*neighbour)).collect()
+    }
+
+    /// Find the Zettels that are within `depth` links of `center`, in either direction.
+    pub fn neighbourhood(&self, center: ZettelId, depth: usize) -> BTreeSet<ZettelId> {
+        let mut found = BTreeSet::from([center]);
+        let mut queue = VecDeque::from([(center, 0)]);
+
+        while let Some((id, distance)) = queue.pop_front() {
+            if distance == depth {
+                continue;
+            }
+
+            for neighbour in self.neighbours(id) {
+                if found.insert(neighbour) {
+                    queue.push_back((neighbour, distance + 1));
+                }
+            }
+        }
+
+        found
+    }
+
+    /// Find the shortest chain of links between two Zettels, following links in either direction. The path
+    /// includes both ends, and is `None` if the Zettels aren't connected.
+    pub fn shortest_path(&self, from: ZettelId, to: ZettelId) -> Option<Vec<ZettelId>> {
+        let mut previous = BTreeMap::new();
+        let mut queue = VecDeque::from([from]);
+        previous.insert(from, from);
+
+        while let Some(id) = queue.pop_front() {
+            if id == to {
+                let mut path = vec![to];
+                let mut current = to;
+                while current != from {
+                    current = previous[&current];
+                    path.push(current);
+                }
+                path.reverse();
+                return Some(path);
+            }
+
+            for neighbour in self.neighbours(id) {
+                if let Entry::Vacant(entry) = previous.entry(neighbour) {
+                    entry.insert(id);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Zettels that don't link to any other Zettel, and aren't linked to by any.
+    pub fn orphans(&self) -> Vec<ZettelId> {
+        self.titles.keys().copied().filter(|id| self.neighbours(*id).is_empty()).collect()
+    }
+
+    /// Get every Zettel along with the number of Zettels linking to it and the number it links to, with the most
+    /// linked-to Zettels first.
+    pub fn hubs(&self) -> Vec<(ZettelId, usize, usize)> {
+        let mut hubs = self
+            .titles
+            .keys()
+            .map(|id| {
+                let incoming = self.backlinks.get(id).map_or(0, |backlinks| backlinks.len());
+                let outgoing = self.links[id].iter().filter(|target| self.contains(**target)).count();
+                (*id, incoming, outgoing)
+            })
+            .collect::<Vec<_>>();
+
+        hubs.sort_by(|(_, a_in, a_out), (_, b_in, b_out)| (b_in, b_out).cmp(&(a_in, a_out)));
+        hubs
+    }
+
+    /// Split the Zettels into groups that are connected to each other by links (in either direction), with the
+    /// largest groups first.
+    pub fn components(&self) -> Vec<BTreeSet<ZettelId>> {
+        let mut seen = BTreeSet::new();
+        let mut components = Vec::new();
+
+        for id in self.titles.keys() {
+            if seen.contains(id) {
+                continue;
+            }
+
+            let component = self.neighbourhood(*id, usize::MAX);
+            seen.extend(component.iter().copied());
+            components.push(component);
+        }
+
+        components.sort_by_key(|component| std::cmp::Reverse(component.len()));
+        components
+    }
+
+    /// Links to Zettels that don't exist, as `(source, target)` pairs.
+    pub fn broken_links(&self) -> Vec<(ZettelId, ZettelId)> {
+        self.links
+            .iter()
+            .flat_map(|(source, targets)| {
+                targets.iter().filter(|target| !self.contains(**target)).map(move |target| (*source, *target))
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Node {
+    pub id: ZettelId,
+    pub title: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Edge {
+    pub source: ZettelId,
+    pub target: ZettelId,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GraphResult {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GraphParams {
+    /// Only return the part of the graph around this Zettel.
+    center: Option<ZettelId>,
+    /// How many links away from `center` to go. Defaults to `1`.
+    depth: Option<usize>,
+}
+
+pub async fn graph(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Query(params): Query<GraphParams>,
+) -> Result<Json<GraphResult>, StatusCode> {
+    let graph = Graph::build(&state.store.visible(&access));
+
+    let included = match params.center {
+        Some(center) if graph.contains(center) => graph.neighbourhood(center, params.depth.unwrap_or(1)),
+        Some(_) => return Err(StatusCode::NOT_FOUND),
+        None => graph.titles.keys().copied().collect(),
+    };
+
+    let nodes = included.iter().map(|id| Node { id: *id, title: graph.titles[id].clone() }).collect();
+    let edges = graph
+        .edges()
+        .filter(|(source, target)| included.contains(source) && included.contains(target))
+        .map(|(source, target)| Edge { source, target })
+        .collect();
+
+    Ok(Json(GraphResult { nodes, edges }))
+}
+
+pub async fn orphans(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<Vec<Node>>, StatusCode> {
+    let graph = Graph::build(&state.store.visible(&access));
+    let orphans = graph.orphans().into_iter().map(|id| Node { id, title: graph.titles[&id].clone() }).collect();
+    Ok(Json(orphans))
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Hub {
+    pub id: ZettelId,
+    pub title: String,
+    pub incoming: usize,
+    pub outgoing: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HubsParams {
+    /// How many hubs to return. Defaults to `10`.
+    limit: Option<usize>,
+}
+
+pub async fn hubs(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Query(params): Query<HubsParams>,
+) -> Result<Json<Vec<Hub>>, StatusCode> {
+    let graph = Graph::build(&state.store.visible(&access));
+    let hubs = graph
+        .hubs()
+        .into_iter()
+        .take(params.limit.unwrap_or(10))
+        .map(|(id, incoming, outgoing)| Hub { id, title: graph.titles[&id].clone(), incoming, outgoing })
+        .collect();
+    Ok(Json(hubs))
+}
+
+pub async fn components(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<Vec<Vec<Node>>>, StatusCode> {
+    let graph = Graph::build(&state.store.visible(&access));
+    let components = graph
+        .components()
+        .into_iter()
+        .map(|component| component.into_iter().map(|id| Node { id, title: graph.titles[&id].clone() }).collect())
+        .collect();
+    Ok(Json(components))
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BrokenLink {
+    pub source: ZettelId,
+    pub source_title: String,
+    pub target: ZettelId,
+}
+
+pub async fn broken(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<Vec<BrokenLink>>, StatusCode> {
+    let graph = Graph::build(&state.store.visible(&access));
+
+    /*
+     * The graph only has the Zettels this user can see, so a link to someone else's private Zettel looks broken
+     * too. Those links aren't broken, and saying they are would give away which Zettels exist, so they're left out.
+     */
+    let broken = graph
+        .broken_links()
+        .into_iter()
+        .filter(|(_, target)| state.store.get(*target).is_none())
+        .map(|(source, target)| BrokenLink { source, source_title: graph.titles[&source].clone(), target })
+        .collect();
+    Ok(Json(broken))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PathParams {
+    from: ZettelId,
+    to: ZettelId,
+}
+
+/// Find the shortest path of links between two Zettels. Returns an empty path if they aren't connected.
+pub async fn path(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Query(params): Query<PathParams>,
+) -> Result<Json<Vec<Node>>, StatusCode> {
+    let graph = Graph::build(&state.store.visible(&access));
+    if !graph.contains(params.from) || !graph.contains(params.to) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let path = graph
+        .shortest_path(params.from, params.to)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|id| Node { id, title: graph.titles[&id].clone() })
+        .collect();
+    Ok(Json(path))
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Related {
+    pub id: ZettelId,
+    pub title: String,
+    /// How many Zettels both this Zettel and the original are linked with.
+    pub shared_links: usize,
+    /// How similar the content of this Zettel is to the original, relative to the most similar Zettel found.
+    pub similarity: f32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RelatedParams {
+    /// How many suggestions to return. Defaults to `10`.
+    limit: Option<usize>,
+}
+
+/// Suggest Zettels that are related to the given one, but aren't linked to or from it yet. Zettels are ranked by
+/// the number of links they share with it, and then by how similar their content is.
+pub async fn related(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(id): Path<ZettelId>,
+    Query(params): Query<RelatedParams>,
+) -> Result<Json<Vec<Related>>, StatusCode> {
+    let zettels = state.store.visible(&access);
+    let graph = Graph::build(&zettels);
+    let record = match zettels.iter().find(|(found, _)| *found == id) {
+        Some((_, record)) => record,
+        None => return Err(StatusCode::NOT_FOUND),
+    };
+    let limit = params.limit.unwrap_or(10);
+
+    let neighbours = graph.neighbours(id);
+    let mut shared_links = BTreeMap::new();
+    for neighbour in &neighbours {
+        for candidate in graph.neighbours(*neighbour) {
+            *shared_links.entry(candidate).or_insert(0) += 1;
+        }
+    }
+
+    let similar = state.index.similar(id, record, limit, &access);
+    let max_score = similar.iter().map(|(_, score)| *score).fold(0.0, f32::max);
+    let similarity = similar
+        .into_iter()
+        .map(|(found, score)| (found, if max_score > 0.0 { score / max_score } else { 0.0 }))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut related = shared_links
+        .keys()
+        .chain(similarity.keys())
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|candidate| *candidate != id && !neighbours.contains(candidate) && graph.contains(*candidate))
+        .map(|candidate| Related {
+            id: candidate,
+            title: graph.titles[&candidate].clone(),
+            shared_links: shared_links.get(&candidate).copied().unwrap_or(0),
+            similarity: similarity.get(&candidate).copied().unwrap_or(0.0),
+        })
+        .collect::<Vec<_>>();
+
+    related.sort_by(|a, b| {
+        b.shared_links.cmp(&a.shared_links).then(b.similarity.partial_cmp(&a.similarity).unwrap())
+    });
+    related.truncate(limit);
+    Ok(Json(related))
+}
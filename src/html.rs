@@ -0,0 +1,342 @@
+//! Rendering of `ZettelContent` to HTML on the server, for read-only views and exports that can't rely on the
+//! frontend's editor. Content comes from users, so everything is escaped, and attributes that could be used to
+//! inject scripts (link targets, image sources, and highlight colours) are checked against an allow-list of safe
+//! values and dropped if they don't match.
+
+use crate::zettel::{Block, Inline, Mark, TableAttrs, ZettelContent};
+use commonplace::ZettelId;
+
+/// Render a Zettel's content to an HTML fragment. `link` is used to render links to other Zettels, as where they
+/// should point (or whether they should be links at all) depends on where the HTML is going.
+pub fn render(content: &ZettelContent, link: &dyn Fn(ZettelId) -> String) -> String {
+    let mut renderer = Renderer { link, heading_prefix: None, headings: Vec::new() };
+    render_content(content, &mut renderer)
+}
+
+#[derive(Clone, Debug)]
+pub struct Heading {
+    pub level: usize,
+    /// The `id` given to the heading's element, so it can be linked to.
+    pub id: String,
+    pub text: String,
+}
+
+/// Render a Zettel's content like `render`, but also give each heading an `id` starting with `heading_prefix`, so
+/// that they can be linked to (e.g. from a table of contents). The headings are returned in the order they appear.
+pub fn render_with_headings(
+    content: &ZettelContent,
+    link: &dyn Fn(ZettelId) -> String,
+    heading_prefix: &str,
+) -> (String, Vec<Heading>) {
+    let mut renderer = Renderer { link, heading_prefix: Some(heading_prefix), headings: Vec::new() };
+    let html = render_content(content, &mut renderer);
+    (html, renderer.headings)
+}
+
+struct Renderer<'a> {
+    link: &'a dyn Fn(ZettelId) -> String,
+    heading_prefix: Option<&'a str>,
+    headings: Vec<Heading>,
+}
+
+fn render_content(content: &ZettelContent, renderer: &mut Renderer) -> String {
+    let mut html = String::new();
+    match content {
+        ZettelContent::Doc { content } => {
+            for block in content {
+                render_block(&mut html, block, renderer);
+            }
+        }
+    }
+    html
+}
+
+/// Render a complete, standalone HTML document with the given title and body.
+pub fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"viewport\" \
+         content=\"width=device-width, initial-scale=1\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<article>\n<h1>{}</h1>\n{}</article>\n</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        escape(title),
+        body
+    )
+}
+
+/// A simple stylesheet for standalone pages, so they're readable without the frontend's styles.
+const STYLE: &str = "body { font-family: sans-serif; line-height: 1.5; max-width: 48em; margin: 2em auto; padding: \
+                     0 1em; color: #222; } pre { background: #f4f4f4; padding: 0.5em; overflow-x: auto; } \
+                     blockquote { border-left: 3px solid #ccc; margin-left: 0; padding-left: 1em; color: #555; } \
+                     table { border-collapse: collapse; } th, td { border: 1px solid #ccc; padding: 0.25em 0.5em; } \
+                     ul[data-type=taskList] { list-style: none; padding-left: 0.5em; } \
+                     li[data-type=taskItem] { display: flex; gap: 0.5em; } img { max-width: 100%; }";
+
+/// Render a link to another Zettel, pointing at where the frontend shows it.
+pub fn zettel_link(id: ZettelId, title: Option<&str>) -> String {
+    let text = match title {
+        Some(title) if !title.is_empty() => title.to_string(),
+        _ => id.0.to_string(),
+    };
+    format!("<a class=\"zettel-link\" href=\"/zettel/{}\">{}</a>", id.0, escape(&text))
+}
+
+fn render_blocks(html: &mut String, blocks: &Option<Vec<Block>>, renderer: &mut Renderer) {
+    for block in blocks.iter().flatten() {
+        render_block(html, block, renderer);
+    }
+}
+
+fn render_inlines(html: &mut String, inlines: &Option<Vec<Inline>>, renderer: &mut Renderer) {
+    for inline in inlines.iter().flatten() {
+        render_inline(html, inline, renderer);
+    }
+}
+
+fn render_block(html: &mut String, block: &Block, renderer: &mut Renderer) {
+    match block {
+        Block::Paragraph { content } => {
+            html.push_str("<p>");
+            render_inlines(html, content, renderer);
+            html.push_str("</p>\n");
+        }
+        Block::Heading { attrs, content } => {
+            let level = attrs.level.clamp(1, 6);
+            match renderer.heading_prefix {
+                Some(prefix) => {
+                    let id = format!("{}-{}", prefix, renderer.headings.len() + 1);
+                    let text = content.iter().flatten().fold(String::new(), |mut text, inline| {
+                        if let Inline::Text { text: inline_text, .. } = inline {
+                            text.push_str(inline_text);
+                        }
+                        text
+                    });
+                    html.push_str(&format!("<h{} id=\"{}\">", level, escape(&id)));
+                    renderer.headings.push(Heading { level, id, text });
+                }
+                None => html.push_str(&format!("<h{}>", level)),
+            }
+            render_inlines(html, content, renderer);
+            html.push_str(&format!("</h{}>\n", level));
+        }
+        Block::CodeBlock { attrs, content } => {
+            match attrs.language.as_deref().filter(|language| is_safe_language(language)) {
+                Some(language) => html.push_str(&format!("<pre><code class=\"language-{}\">", language)),
+                None => html.push_str("<pre><code>"),
+            }
+            for inline in content.iter().flatten() {
+                if let Inline::Text { text, .. } = inline {
+                    html.push_str(&escape(text));
+                }
+            }
+            html.push_str("</code></pre>\n");
+        }
+        Block::Blockquote { content } => {
+            html.push_str("<blockquote>\n");
+            render_blocks(html, content, renderer);
+            html.push_str("</blockquote>\n");
+        }
+        Block::BulletList { content } => {
+            html.push_str("<ul>\n");
+            render_blocks(html, content, renderer);
+            html.push_str("</ul>\n");
+        }
+        Block::OrderedList { content } => {
+            html.push_str("<ol>\n");
+            render_blocks(html, content, renderer);
+            html.push_str("</ol>\n");
+        }
+        Block::ListItem { content } => {
+            html.push_str("<li>");
+            render_blocks(html, content, renderer);
+            html.push_str("</li>\n");
+        }
+        Block::TaskList { content } => {
+            html.push_str("<ul data-type=\"taskList\">\n");
+            render_blocks(html, content, renderer);
+            html.push_str("</ul>\n");
+        }
+        Block::TaskItem { attrs, content } => {
+            let checked = if attrs.checked { " checked" } else { "" };
+            html.push_str(&format!(
+                "<li data-type=\"taskItem\" data-checked=\"{}\"><label><input type=\"checkbox\" disabled{}></label><div>",
+                attrs.checked, checked
+            ));
+            render_blocks(html, content, renderer);
+            html.push_str("</div></li>\n");
+        }
+        Block::Table { content } => {
+            html.push_str("<table>\n<tbody>\n");
+            render_blocks(html, content, renderer);
+            html.push_str("</tbody>\n</table>\n");
+        }
+        Block::TableRow { content } => {
+            html.push_str("<tr>");
+            render_blocks(html, content, renderer);
+            html.push_str("</tr>\n");
+        }
+        Block::TableHeader { attrs, content } => {
+            html.push_str(&format!("<th{}>", table_cell_attrs(attrs)));
+            render_blocks(html, content, renderer);
+            html.push_str("</th>");
+        }
+        Block::TableCell { attrs, content } => {
+            html.push_str(&format!("<td{}>", table_cell_attrs(attrs)));
+            render_blocks(html, content, renderer);
+            html.push_str("</td>");
+        }
+        Block::HorizontalRule => html.push_str("<hr>\n"),
+        Block::Image { attrs } => {
+            if let Some(src) = Some(attrs.src.as_str()).filter(|src| is_safe_url(src, true)) {
+                html.push_str(&format!("<img src=\"{}\"", escape(src)));
+                if let Some(alt) = &attrs.alt {
+                    html.push_str(&format!(" alt=\"{}\"", escape(alt)));
+                }
+                if let Some(title) = &attrs.title {
+                    html.push_str(&format!(" title=\"{}\"", escape(title)));
+                }
+                html.push_str(">\n");
+            }
+        }
+        Block::Details { attrs, content } => {
+            html.push_str(if attrs.open { "<details open>\n" } else { "<details>\n" });
+            render_blocks(html, content, renderer);
+            html.push_str("</details>\n");
+        }
+        Block::DetailsSummary { content } => {
+            html.push_str("<summary>");
+            render_inlines(html, content, renderer);
+            html.push_str("</summary>\n");
+        }
+        Block::DetailsContent { content } => {
+            html.push_str("<div data-type=\"detailsContent\">\n");
+            render_blocks(html, content, renderer);
+            html.push_str("</div>\n");
+        }
+    }
+}
+
+fn table_cell_attrs(attrs: &TableAttrs) -> String {
+    let mut result = String::new();
+    if attrs.colspan != 1 {
+        result.push_str(&format!(" colspan=\"{}\"", attrs.colspan));
+    }
+    if attrs.rowspan != 1 {
+        result.push_str(&format!(" rowspan=\"{}\"", attrs.rowspan));
+    }
+    if let Some(widths) = &attrs.colwidth {
+        let widths = widths.iter().map(|width| width.to_string()).collect::<Vec<_>>();
+        result.push_str(&format!(" data-colwidth=\"{}\"", widths.join(",")));
+        if let [width] = attrs.colwidth.as_deref().unwrap() {
+            result.push_str(&format!(" style=\"width: {}px\"", width));
+        }
+    }
+    result
+}
+
+fn render_inline(html: &mut String, inline: &Inline, renderer: &mut Renderer) {
+    match inline {
+        Inline::ZettelLink { attrs } => html.push_str(&(renderer.link)(ZettelId(attrs.target))),
+        Inline::Text { text, marks } => {
+            let marks = marks.as_deref().unwrap_or(&[]);
+            let mut closing = Vec::new();
+
+            for mark in marks {
+                let (open, close) = match mark {
+                    Mark::Bold => ("<strong>".to_string(), "</strong>"),
+                    Mark::Italic => ("<em>".to_string(), "</em>"),
+                    Mark::Strike => ("<s>".to_string(), "</s>"),
+                    Mark::Code => ("<code>".to_string(), "</code>"),
+                    Mark::Superscript => ("<sup>".to_string(), "</sup>"),
+                    Mark::Subscript => ("<sub>".to_string(), "</sub>"),
+                    Mark::Highlight { color: Some(color) } if is_safe_color(color) => (
+                        format!("<mark data-color=\"{}\" style=\"background-color: {}\">", color, color),
+                        "</mark>",
+                    ),
+                    Mark::Highlight { .. } => ("<mark>".to_string(), "</mark>"),
+                    Mark::Link { attrs } if is_safe_url(&attrs.href, false) => {
+                        let target = match attrs.target.as_str() {
+                            "_blank" | "_self" | "_parent" | "_top" => {
+                                format!(" target=\"{}\"", attrs.target)
+                            }
+                            _ => String::new(),
+                        };
+                        (
+                            format!(
+                                "<a href=\"{}\"{} rel=\"noopener noreferrer nofollow\">",
+                                escape(&attrs.href),
+                                target
+                            ),
+                            "</a>",
+                        )
+                    }
+                    Mark::Link { .. } => continue,
+                };
+                html.push_str(&open);
+                closing.push(close);
+            }
+
+            html.push_str(&escape(text).replace('\n', "<br>"));
+            for close in closing.into_iter().rev() {
+                html.push_str(close);
+            }
+        }
+    }
+}
+
+pub fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Only allow URLs that can't run scripts: web and email links, and relative links. Images can also be inline
+/// data, as long as it's actually an image.
+fn is_safe_url(url: &str, image: bool) -> bool {
+    let url = url.trim().to_lowercase();
+
+    /*
+     * A URL without a scheme is relative. Browsers ignore some characters when looking for a scheme, so be careful
+     * not to let something like `java\tscript:` through.
+     */
+    let scheme = match url.find(':') {
+        Some(colon) if !url[..colon].contains(['/', '?', '#']) => &url[..colon],
+        _ => return true,
+    };
+
+    match scheme {
+        "http" | "https" => true,
+        "mailto" => !image,
+        "data" => image && url.starts_with("data:image/") && !url.starts_with("data:image/svg"),
+        _ => false,
+    }
+}
+
+/// Highlight colours end up in a `style` attribute, so we only allow things that look like simple CSS colours.
+fn is_safe_color(color: &str) -> bool {
+    if let Some(hex) = color.strip_prefix('#') {
+        return matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit());
+    }
+
+    if let Some(arguments) =
+        ["rgb(", "rgba(", "hsl(", "hsla("].iter().find_map(|function| color.strip_prefix(function))
+    {
+        return arguments.strip_suffix(')').is_some_and(|arguments| {
+            arguments.chars().all(|c| c.is_ascii_digit() || matches!(c, ',' | '.' | '%' | ' ' | '/'))
+        });
+    }
+
+    !color.is_empty() && color.chars().all(|c| c.is_ascii_alphabetic())
+}
+
+fn is_safe_language(language: &str) -> bool {
+    language.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '#'))
+}
@@ -0,0 +1,121 @@
+//! Importing whole collections of Markdown notes, as kept by tools like Obsidian and Logseq (which call them
+//! "vaults" and "graphs" respectively). Each Markdown file becomes a Zettel, and wiki-style links between them are
+//! turned into links between the new Zettels.
+
+use crate::{
+    index::Index,
+    markdown,
+    store::{Permissions, ZettelStore},
+    zettel::ZettelUpdate,
+};
+use chrono::{DateTime, Utc};
+use commonplace::ZettelId;
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+struct Note {
+    path: PathBuf,
+    /// The name that other notes use to link to this one - its file name, without the extension.
+    name: String,
+    modified: DateTime<Utc>,
+}
+
+/// Import every Markdown file in `dir` (and its subdirectories) as a new Zettel. Each Zettel's ID is based on when
+/// its file was last modified, as that's the closest thing we have to when it was created. Every Zettel is given
+/// the same `permissions`. Returns the ID that each file was imported as.
+pub fn import_vault(
+    store: &ZettelStore,
+    index: &Index,
+    dir: &Path,
+    permissions: &Permissions,
+) -> io::Result<Vec<(PathBuf, ZettelId)>> {
+    let mut notes = Vec::new();
+    find_notes(dir, &mut notes)?;
+    notes.sort_by_key(|note| note.modified);
+
+    /*
+     * Read every file before creating anything, so a file we can't read (e.g. one that isn't UTF-8) doesn't leave
+     * behind half an import.
+     */
+    let texts = notes
+        .iter()
+        .map(|note| {
+            fs::read_to_string(&note.path).map_err(|err| {
+                io::Error::new(err.kind(), format!("Couldn't read {}: {}", note.path.display(), err))
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    /*
+     * We need to know the IDs of all the new Zettels before we can resolve links between them, so we create them
+     * all up front and fill them in afterwards.
+     */
+    let ids = notes.iter().map(|note| store.create_at(note.modified, permissions.clone())).collect::<Vec<_>>();
+
+    let mut names = HashMap::new();
+    for (note, id) in notes.iter().zip(&ids) {
+        names.insert(note.name.to_lowercase(), *id);
+        let relative = note.path.strip_prefix(dir).unwrap().with_extension("");
+        names.insert(relative.to_string_lossy().replace('\\', "/").to_lowercase(), *id);
+    }
+    let resolve = |target: &str| {
+        /*
+         * Links can point at a heading or block within a note (`Note#Heading` or `Note#^block`), but we can only
+         * link to the note as a whole.
+         */
+        let target = target.split(['#', '^']).next().unwrap().trim();
+        let target = target.strip_suffix(".md").unwrap_or(target);
+        names.get(&target.to_lowercase()).copied()
+    };
+
+    let mut imported = Vec::new();
+    for ((note, text), id) in notes.into_iter().zip(texts).zip(ids) {
+        let parsed = markdown::parse(&text, &resolve);
+        let title = parsed.title.unwrap_or_else(|| note.name.clone());
+
+        store.update(id, ZettelUpdate { title, content: parsed.content, tags: None });
+        info!("Imported {} as Zettel {}", note.path.display(), id.0);
+        imported.push((note.path, id));
+    }
+
+    let records = imported.iter().map(|(_, id)| (*id, store.get(*id).unwrap())).collect::<Vec<_>>();
+    index.update_zettels(&records);
+
+    Ok(imported)
+}
+
+fn find_notes(dir: &Path, notes: &mut Vec<Note>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        /*
+         * Skip hidden files and directories, which include things like Obsidian's `.obsidian` settings and
+         * `.trash` directories.
+         */
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            find_notes(&path, notes)?;
+        } else if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("md")) {
+            let stem = path.file_stem().unwrap().to_string_lossy();
+            let modified = DateTime::<Utc>::from(entry.metadata()?.modified()?);
+            notes.push(Note { name: note_name(&stem), path, modified });
+        }
+    }
+
+    Ok(())
+}
+
+/// Logseq can't put `/` in file names, so it encodes it as `___` (or `%2F` in older versions). We turn them back
+/// into `/`, so links to namespaced pages like `[[project/commonplace]]` resolve.
+fn note_name(stem: &str) -> String {
+    stem.replace("___", "/").replace("%2F", "/").replace("%2f", "/")
+}
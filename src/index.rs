@@ -1,4 +1,4 @@
-use crate::store::ZettelRecord;
+use crate::{store::ZettelRecord, workspace::Access};
 use commonplace::ZettelId;
 use std::{
     path::Path,
@@ -7,26 +7,36 @@ use std::{
         Arc,
         Mutex,
     },
+    time::Duration,
 };
 use tantivy::{
     collector::TopDocs,
     directory::MmapDirectory,
     doc,
-    query::QueryParser,
-    schema::{Field, OwnedValue, Schema, Term, FAST, INDEXED, STORED, TEXT},
+    query::{AllQuery, BooleanQuery, BoostQuery, MoreLikeThisQuery, Occur, Query, QueryParser, TermQuery},
+    schema::{Field, IndexRecordOption, OwnedValue, Schema, Term, FAST, INDEXED, STORED, STRING, TEXT},
     Index as TantivyIndex,
     IndexWriter,
+    ReloadPolicy,
     TantivyDocument,
+    TantivyError,
 };
+use tracing::warn;
 
 struct Fields {
     id: Field,
     title: Field,
     content: Field,
+    tags: Field,
+    /// The access keys that can see each Zettel (see `Permissions::readers`), so search results can be limited to
+    /// what the user is allowed to see.
+    readers: Field,
 }
 
 pub struct Index {
     pub commit_needed: AtomicBool,
+    /// Set if the index was created from scratch when it was opened, and so needs to be rebuilt from the store.
+    pub needs_rebuild: bool,
     index: TantivyIndex,
     fields: Fields,
     index_writer: Mutex<IndexWriter>,
@@ -34,22 +44,25 @@ pub struct Index {
 }
 
 impl Index {
-    pub fn new() -> Arc<Index> {
-        let mut schema_builder = Schema::builder();
-        let id = schema_builder.add_u64_field("id", INDEXED | FAST | STORED);
-        let title = schema_builder.add_text_field("title", TEXT);
-        let content = schema_builder.add_text_field("content", TEXT);
-        let schema = schema_builder.build();
-
-        let index =
-            TantivyIndex::open_or_create(MmapDirectory::open(Path::new("index/")).unwrap(), schema).unwrap();
-        let writer = index.writer(50_000_000).unwrap();
-        let query_parser = QueryParser::for_index(&index, vec![title, content]);
+    /// Open the index in `path`, creating it if needed. If `in_memory` is set, the index is only kept in memory
+    /// instead, and always needs to be rebuilt - this is used when Zettels are encrypted, so their contents never
+    /// end up on disk unencrypted.
+    pub fn new(path: &Path, writer_heap_bytes: usize, in_memory: bool) -> Arc<Index> {
+        let (schema, fields) = schema();
+        let (index, needs_rebuild) = if in_memory {
+            remove_from_disk(path);
+            (TantivyIndex::create_in_ram(schema), true)
+        } else {
+            open_on_disk(path, schema)
+        };
+        let writer = index.writer(writer_heap_bytes).unwrap();
+        let query_parser = QueryParser::for_index(&index, vec![fields.title, fields.content]);
 
         Arc::new(Index {
             commit_needed: AtomicBool::new(false),
+            needs_rebuild,
             index,
-            fields: Fields { id, title, content },
+            fields,
             index_writer: Mutex::new(writer),
             query_parser,
         })
@@ -57,22 +70,69 @@ impl Index {
 
     pub fn update_zettel(&self, id: ZettelId, new: &ZettelRecord) {
         let index_writer = self.index_writer.lock().unwrap();
+        self.add_zettel(&index_writer, id, new);
+        self.commit_needed.store(true, Ordering::SeqCst);
+    }
+
+    /// Update a batch of Zettels together. Unlike `update_zettel`, this commits the index straight away, so the
+    /// changes become visible all at once.
+    pub fn update_zettels(&self, zettels: &[(ZettelId, ZettelRecord)]) {
+        let mut index_writer = self.index_writer.lock().unwrap();
+        for (id, record) in zettels {
+            self.add_zettel(&index_writer, *id, record);
+        }
+        index_writer.commit().unwrap();
+    }
+
+    /// Throw away everything in the index, and re-add every Zettel in the store. This commits the index when it's
+    /// done, so the Zettels are immediately searchable.
+    pub fn rebuild(&self, zettels: Vec<(ZettelId, ZettelRecord)>) {
+        let mut index_writer = self.index_writer.lock().unwrap();
+        index_writer.delete_all_documents().unwrap();
+        for (id, record) in &zettels {
+            self.add_zettel(&index_writer, *id, record);
+        }
+        index_writer.commit().unwrap();
+    }
+
+    fn add_zettel(&self, index_writer: &IndexWriter, id: ZettelId, record: &ZettelRecord) {
         index_writer.delete_term(Term::from_field_u64(self.fields.id, id.0));
-        index_writer
-            .add_document(tantivy::doc!(
-                self.fields.id => id.0,
-                self.fields.title => new.title.clone(),
-                self.fields.content => new.content.index(),
-            ))
-            .unwrap();
 
-        self.commit_needed.store(true, Ordering::SeqCst);
+        let mut document = tantivy::doc!(
+            self.fields.id => id.0,
+            self.fields.title => record.title.clone(),
+            self.fields.content => record.content.index(),
+        );
+        for tag in &record.tags {
+            document.add_text(self.fields.tags, tag);
+        }
+        for reader in record.permissions.readers() {
+            document.add_text(self.fields.readers, reader);
+        }
+        index_writer.add_document(document).unwrap();
     }
 
-    pub fn search(&self, query: &str) -> Vec<ZettelId> {
+    /// Search the index for Zettels matching `query` that can be seen with `access`. If `tags` is not empty, only
+    /// Zettels with all of the given tags are returned, and an empty query matches every Zettel with those tags.
+    pub fn search(&self, query: &str, tags: &[String], access: &Access) -> Vec<ZettelId> {
         let reader = self.index.reader().unwrap();
         let searcher = reader.searcher();
-        let query = self.query_parser.parse_query(query).unwrap();
+
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = tags
+            .iter()
+            .map(|tag| {
+                let term = Term::from_field_text(self.fields.tags, tag);
+                (Occur::Must, Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>)
+            })
+            .collect();
+        if !query.trim().is_empty() || clauses.is_empty() {
+            clauses.push((Occur::Must, self.query_parser.parse_query(query).unwrap()));
+        } else {
+            clauses.push((Occur::Must, Box::new(AllQuery)));
+        }
+        clauses.push((Occur::Must, self.access_filter(access)));
+        let query = BooleanQuery::new(clauses);
+
         let top_docs = searcher.search(&query, &TopDocs::with_limit(10)).unwrap();
 
         top_docs
@@ -86,13 +146,130 @@ impl Index {
             })
             .collect()
     }
+
+    /// Find up to `limit` Zettels that can be seen with `access` and have content similar to the given Zettel, along
+    /// with how similar they are. The Zettel itself is not included.
+    pub fn similar(
+        &self,
+        id: ZettelId,
+        record: &ZettelRecord,
+        limit: usize,
+        access: &Access,
+    ) -> Vec<(ZettelId, f32)> {
+        let reader = self.index.reader().unwrap();
+        let searcher = reader.searcher();
+
+        /*
+         * The defaults are tuned for large corpuses, which most collections of notes are not, so we consider any
+         * term that appears at all.
+         */
+        let query = MoreLikeThisQuery::builder()
+            .with_min_doc_frequency(1)
+            .with_min_term_frequency(1)
+            .with_min_word_length(3)
+            .with_document_fields(vec![
+                (self.fields.title, vec![OwnedValue::Str(record.title.clone())]),
+                (self.fields.content, vec![OwnedValue::Str(record.content.index())]),
+            ]);
+        let query =
+            BooleanQuery::new(vec![(Occur::Must, Box::new(query)), (Occur::Must, self.access_filter(access))]);
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit + 1)).unwrap();
+
+        top_docs
+            .iter()
+            .map(|(score, doc_address)| {
+                let doc = searcher.doc::<TantivyDocument>(*doc_address).unwrap();
+                match doc.get_first(self.fields.id) {
+                    Some(OwnedValue::U64(id)) => (ZettelId(*id), *score),
+                    _ => panic!("Index produced incorrect type for Zettel ID"),
+                }
+            })
+            .filter(|(found, _)| *found != id)
+            .take(limit)
+            .collect()
+    }
+
+    /// A query that matches the Zettels that can be seen with `access`. This doesn't contribute to the score, so it
+    /// can be combined with other queries without changing how their results are ranked.
+    fn access_filter(&self, access: &Access) -> Box<dyn Query> {
+        let readers = access
+            .keys()
+            .into_iter()
+            .map(|key| {
+                let term = Term::from_field_text(self.fields.readers, &key);
+                (Occur::Should, Box::new(TermQuery::new(term, IndexRecordOption::Basic)) as Box<dyn Query>)
+            })
+            .collect();
+        Box::new(BoostQuery::new(Box::new(BooleanQuery::new(readers)), 0.0))
+    }
+}
+
+fn schema() -> (Schema, Fields) {
+    let mut schema_builder = Schema::builder();
+    let id = schema_builder.add_u64_field("id", INDEXED | FAST | STORED);
+    let title = schema_builder.add_text_field("title", TEXT);
+    let content = schema_builder.add_text_field("content", TEXT);
+    let tags = schema_builder.add_text_field("tags", STRING);
+    let readers = schema_builder.add_text_field("readers", STRING);
+    (schema_builder.build(), Fields { id, title, content, tags, readers })
+}
+
+/// Count the Zettels in the index in `path`, without changing it - unlike `Index::new`, this doesn't create the
+/// index, rebuild it if its schema has changed, or need the lock that stops two processes writing to it. If the
+/// index is missing or needs rebuilding, returns what's wrong with it instead.
+pub fn count_on_disk(path: &Path) -> Result<u64, String> {
+    let missing = || format!("There's no search index in {}", path.display());
+    let directory = MmapDirectory::open(path).map_err(|_| missing())?;
+    if !TantivyIndex::exists(&directory).unwrap() {
+        return Err(missing());
+    }
+
+    let index = TantivyIndex::open(directory)
+        .map_err(|err| format!("Can't open the search index in {}: {}", path.display(), err))?;
+    if index.schema() != schema().0 {
+        return Err(format!("The search index in {} has an old schema, so needs rebuilding", path.display()));
+    }
+    let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into().unwrap();
+    Ok(reader.searcher().num_docs())
+}
+
+/// Open the index in `path`, returning whether it needs to be rebuilt because it's new or its schema has changed.
+fn open_on_disk(path: &Path, schema: Schema) -> (TantivyIndex, bool) {
+    std::fs::create_dir_all(path).unwrap();
+    let directory = MmapDirectory::open(path).unwrap();
+    let mut needs_rebuild = !TantivyIndex::exists(&directory).unwrap();
+    let index = match TantivyIndex::open_or_create(directory, schema.clone()) {
+        Ok(index) => index,
+        /*
+         * The index is entirely derived from the store, so if its schema has changed, we can just throw it
+         * away and rebuild it from scratch.
+         */
+        Err(TantivyError::SchemaError(err)) => {
+            warn!("Index schema has changed ({}). Rebuilding index.", err);
+            std::fs::remove_dir_all(path).unwrap();
+            std::fs::create_dir(path).unwrap();
+            needs_rebuild = true;
+            TantivyIndex::create(MmapDirectory::open(path).unwrap(), schema, Default::default()).unwrap()
+        }
+        Err(err) => panic!("Failed to open index: {:?}", err),
+    };
+    (index, needs_rebuild)
+}
+
+/// Delete the index in `path`, if there is one. This is done when encryption is turned on, as the index contains
+/// the text of every Zettel.
+fn remove_from_disk(path: &Path) {
+    if path.exists() && TantivyIndex::exists(&MmapDirectory::open(path).unwrap()).unwrap() {
+        warn!("Zettels are encrypted, so removing the unencrypted index in {}", path.display());
+        std::fs::remove_dir_all(path).unwrap();
+    }
 }
 
 /// Committing the index is way too slow to be doing on every Zettel update, as it pushes up the response time of
 /// update requests. Instead, we run a background task that periodically commits the index if needed - search
 /// results are not available until this happens, but I think that's okay for our purposes.
-pub async fn commit_index(index: Arc<Index>) {
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
+pub async fn commit_index(index: Arc<Index>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
     loop {
         interval.tick().await;
         if index.commit_needed.compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
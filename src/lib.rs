@@ -1,4 +1,4 @@
-use chrono::{Datelike, Timelike, Utc};
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
 use serde::{Deserialize, Serialize};
 
 /// Each Zettel is associated with a unique ID, which is based on a timestamp of when the Zettel was created,
@@ -7,13 +7,17 @@ use serde::{Deserialize, Serialize};
 /// allows a Zettel to be created every second, which I think will be okay for real-world purposes.
 ///
 /// The time in the timestamp is UTC+0, and the date uses the Holocene calendar.
-#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Serialize, Deserialize)]
 #[repr(transparent)]
 pub struct ZettelId(pub u64);
 
 impl ZettelId {
     pub fn generate() -> ZettelId {
-        let datetime = Utc::now();
+        ZettelId::from_datetime(Utc::now())
+    }
+
+    /// Create the ID of a Zettel created at the given time.
+    pub fn from_datetime(datetime: DateTime<Utc>) -> ZettelId {
         let year = datetime.year() as u64 + 10000;
         let month = datetime.month() as u64;
         let day = datetime.day() as u64;
@@ -31,6 +35,18 @@ impl ZettelId {
         )
     }
 
+    /// Get the time that the Zettel with this ID was created. Returns `None` if the ID is not a valid timestamp.
+    pub fn datetime(&self) -> Option<DateTime<Utc>> {
+        let second = self.0 % 100;
+        let minute = (self.0 / 1_00) % 100;
+        let hour = (self.0 / 1_00_00) % 100;
+        let day = (self.0 / 1_00_00_00) % 100;
+        let month = (self.0 / 1_00_00_00_00) % 100;
+        let year = (self.0 / 1_00_00_00_00_00) as i32 - 10000;
+
+        Utc.with_ymd_and_hms(year, month as u32, day as u32, hour as u32, minute as u32, second as u32).single()
+    }
+
     pub fn encode(&self) -> [u8; 8] {
         /*
          * NOTE: when we encode Zettel IDs to be used as keys in the `sled` database, we do so in big-endian. This
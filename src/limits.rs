@@ -0,0 +1,200 @@
+//! Limits on how much a single client can send to the API, so a misbehaving script can't fill the disk or starve
+//! everyone else. Request bodies are limited in size (larger ones get a `413 Payload Too Large`), and each client
+//! gets a bucket of requests that refills at a steady rate (once it's empty, they get a `429 Too Many Requests`).
+//!
+//! Clients are told apart by their IP address. IPv6 clients usually have a whole /64 network to themselves, so
+//! they're told apart by that instead, or they could just use another address when their bucket runs out. Behind a
+//! reverse proxy every request comes from the proxy, so the address it adds to the end of the `X-Forwarded-For`
+//! header can be used instead - but only do this if there is a proxy, as otherwise clients could set the header
+//! themselves to dodge the limit.
+//!
+//! For now, this is configured through environment variables:
+//!    - `COMMONPLACE_MAX_BODY_BYTES` - the largest request body the API accepts (default 2MiB)
+//!    - `COMMONPLACE_RATE_LIMIT` - how many requests a client can make per minute (default 600). Setting this to `0`
+//!      turns rate limiting off.
+//!    - `COMMONPLACE_RATE_BURST` - how many requests a client can make at once, before the rate limit kicks in
+//!      (default 60)
+//!    - `COMMONPLACE_TRUST_FORWARDED_FOR` - whether to identify clients by `X-Forwarded-For` (default `false`)
+
+use crate::AppState;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Once we're tracking this many clients, the one that made a request least recently is forgotten whenever a new
+/// one turns up, so the map doesn't grow forever.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct LimitsConfig {
+    pub max_body_bytes: usize,
+    /// How many requests a client can make per minute. If this is `None`, rate limiting is turned off.
+    pub rate_per_minute: Option<u32>,
+    pub burst: u32,
+    pub trust_forwarded_for: bool,
+}
+
+impl LimitsConfig {
+    pub fn from_env() -> LimitsConfig {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let number = |name: &str, default: u64| {
+            var(name)
+                .map(|value| value.parse::<u64>().unwrap_or_else(|_| panic!("{} should be a number", name)))
+                .unwrap_or(default)
+        };
+
+        let rate =
+            u32::try_from(number("COMMONPLACE_RATE_LIMIT", 600)).expect("COMMONPLACE_RATE_LIMIT is too big");
+        let burst =
+            u32::try_from(number("COMMONPLACE_RATE_BURST", 60)).expect("COMMONPLACE_RATE_BURST is too big");
+        LimitsConfig {
+            max_body_bytes: usize::try_from(number("COMMONPLACE_MAX_BODY_BYTES", 2 * 1024 * 1024))
+                .expect("COMMONPLACE_MAX_BODY_BYTES is too big"),
+            rate_per_minute: if rate == 0 { None } else { Some(rate) },
+            burst: burst.max(1),
+            trust_forwarded_for: var("COMMONPLACE_TRUST_FORWARDED_FOR")
+                .map(|trust| {
+                    trust.parse::<bool>().expect("COMMONPLACE_TRUST_FORWARDED_FOR should be true or false")
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+pub struct RateLimiter {
+    pub config: LimitsConfig,
+    clients: Mutex<Clients>,
+}
+
+#[derive(Default)]
+struct Clients {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Every request, oldest first, so the client that made a request least recently can be found. Finding and
+    /// removing a client's earlier requests would mean searching for them, so they're left here, and skipped over
+    /// (as their time doesn't match the bucket's) when they reach the front.
+    seen: VecDeque<(IpAddr, Instant)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    /// How many requests the client can make right now. This is fractional, as the bucket refills continuously.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: LimitsConfig) -> RateLimiter {
+        RateLimiter { config, clients: Mutex::new(Clients::default()) }
+    }
+
+    /// Take a request from a client's bucket. If it's empty, returns how many seconds until it won't be.
+    fn take(&self, client: IpAddr) -> Result<(), u64> {
+        let Some(rate) = self.config.rate_per_minute else {
+            return Ok(());
+        };
+        let per_second = rate as f64 / 60.0;
+        let burst = self.config.burst as f64;
+        let now = Instant::now();
+
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.buckets.contains_key(&client) {
+            if clients.buckets.len() >= MAX_TRACKED_CLIENTS {
+                clients.forget_least_recent();
+            }
+            clients.buckets.insert(client, Bucket { tokens: burst, updated: now });
+        }
+
+        let bucket = clients.buckets.get_mut(&client).unwrap();
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second).min(burst);
+        bucket.updated = now;
+
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / per_second).ceil() as u64)
+        };
+        clients.record_request(client, now);
+        result
+    }
+
+    fn client(&self, headers: &HeaderMap, address: SocketAddr) -> IpAddr {
+        if self.config.trust_forwarded_for {
+            let forwarded = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|client| client.trim().parse().ok());
+            if let Some(client) = forwarded {
+                return network(client);
+            }
+        }
+        network(address.ip())
+    }
+}
+
+impl Clients {
+    fn record_request(&mut self, client: IpAddr, at: Instant) {
+        self.seen.push_back((client, at));
+
+        /*
+         * Clients that keep making requests would fill this up with their earlier ones, so every so often they're
+         * cleared out. Afterwards there's at most one request per client, so this only happens once every
+         * `MAX_TRACKED_CLIENTS` requests at most, and takes constant time per request on average.
+         */
+        if self.seen.len() > 2 * MAX_TRACKED_CLIENTS {
+            let buckets = &self.buckets;
+            self.seen.retain(|(client, at)| buckets.get(client).is_some_and(|bucket| bucket.updated == *at));
+        }
+    }
+
+    /// Forget the client that made a request least recently. Every request is only skipped over once, so this takes
+    /// constant time on average.
+    fn forget_least_recent(&mut self) {
+        while let Some((client, at)) = self.seen.pop_front() {
+            if self.buckets.get(&client).is_some_and(|bucket| bucket.updated == at) {
+                self.buckets.remove(&client);
+                return;
+            }
+        }
+    }
+}
+
+/// The network a client is rate limited by: its address for IPv4, and its /64 for IPv6. IPv4 addresses mapped into
+/// IPv6 are treated as the IPv4 addresses they are.
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 64))),
+        },
+    }
+}
+
+/// Middleware that rejects requests from clients that have used up their rate limit.
+pub async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = state.limits.client(request.headers(), address);
+    match state.limits.take(client) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, retry_after.to_string())]).into_response()
+        }
+    }
+}
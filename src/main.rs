@@ -1,24 +1,146 @@
+mod admin;
+mod audit;
+mod auth;
+mod backup;
+mod config;
+mod encryption;
+mod export;
+mod graph;
+mod html;
+mod import;
 mod index;
+mod limits;
+mod markdown;
+mod oidc;
+mod print;
+mod publish;
+mod share;
+mod snapshot;
 mod store;
+mod tags;
+mod terminal;
+mod tokens;
+mod workspace;
 mod zettel;
 
+use audit::AuditLog;
+use auth::UserStore;
 use axum::{
+    extract::DefaultBodyLimit,
     http::StatusCode,
+    middleware,
     routing::{get, post},
     Router,
 };
+use clap::{Parser, Subcommand};
+use commonplace::ZettelId;
+use config::{Config, ConfigArgs};
 use index::Index;
-use std::sync::Arc;
-use store::ZettelStore;
+use limits::{LimitsConfig, RateLimiter};
+use oidc::{Oidc, OidcConfig};
+use snapshot::{SnapshotConfig, Snapshots};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+use store::{Permissions, Visibility, ZettelStore};
+use terminal::{Client, ClientArgs};
 use tower_http::{
     services::{ServeDir, ServeFile},
     trace::TraceLayer,
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use workspace::WorkspaceStore;
 
 struct AppState {
     store: ZettelStore,
     index: Arc<Index>,
+    snapshots: Arc<Snapshots>,
+    users: UserStore,
+    workspaces: WorkspaceStore,
+    audit: AuditLog,
+    limits: RateLimiter,
+    /// Single sign-on, if it's configured.
+    oidc: Option<Oidc>,
+}
+
+impl AppState {
+    /// Run `f` on a thread where it's fine to block. Changes to the store wait while a snapshot is being taken (see
+    /// `ZettelStore::pause_writes`), so handlers make them like this rather than stalling the async runtime.
+    async fn blocking<T: Send + 'static>(self: &Arc<Self>, f: impl FnOnce(&AppState) -> T + Send + 'static) -> T {
+        let state = self.clone();
+        tokio::task::spawn_blocking(move || f(&state)).await.unwrap()
+    }
+}
+
+/// A Zettelkasten - a collection of linked notes. With no command, runs the server.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/*
+ * Apart from `serve`, these need exclusive access to the database, so the server can't be running at the same time.
+ */
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Rebuild the search index from the database
+    Reindex,
+    /// Check the database and search index for problems
+    Check,
+    /// Rewrite every Zettel in the current format, and rebuild the indexes that are derived from them
+    Migrate,
+    /// Show statistics about the database and search index
+    Stats,
+    /// Import a directory of Markdown notes (e.g. an Obsidian vault)
+    Import {
+        dir: PathBuf,
+        /// The user who will own the imported Zettels, which only they can see. Needed once there are users
+        #[arg(long)]
+        owner: Option<String>,
+    },
+    /// Export every Zettel to a zip archive of Markdown files
+    Export { file: PathBuf },
+    /// Publish public Zettels as a static website. If any tags are given, only Zettels with one of them are
+    /// published
+    Publish { dir: PathBuf, tags: Vec<String> },
+    /// Back up the whole database to a newline-delimited JSON file
+    Backup { file: PathBuf },
+    /// Restore a backup into an empty database
+    Restore { file: PathBuf },
+    /// List the Zettels you can see
+    List {
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Search for Zettels
+    Search {
+        query: String,
+        /// Only include Zettels with this tag. Can be given more than once
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
+    /// Edit a Zettel as Markdown in $VISUAL or $EDITOR
+    Edit {
+        id: u64,
+        /// Edit the Zettel even if it has formatting that Markdown can't represent, which will be lost
+        #[arg(long)]
+        force: bool,
+        #[command(flatten)]
+        client: ClientArgs,
+    },
 }
 
 #[tokio::main]
@@ -28,10 +150,209 @@ pub async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let index = Index::new();
-    tokio::spawn(index::commit_index(index.clone()));
+    let cli = Cli::parse();
+    let config = Config::load(cli.config);
+    match cli.command {
+        None | Some(Command::Serve) => serve(config).await,
+        Some(Command::Reindex) => {
+            let store = ZettelStore::new(&config.db);
+            let Some(index) = open_index_on_disk(&config, &store) else {
+                println!("Zettels are encrypted, so the search index is only kept in memory and doesn't need rebuilding");
+                return;
+            };
+            let zettels = store.all();
+            let count = zettels.len();
+            index.rebuild(zettels);
+            println!("Reindexed {} Zettels", count);
+        }
+        Some(Command::Check) => {
+            let store = ZettelStore::new(&config.db);
+            let problems = admin::check(&store, count_index_on_disk(&config, &store));
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                eprintln!(
+                    "Found {} problems. Running `migrate` and `reindex` may fix some of them.",
+                    problems.len()
+                );
+                std::process::exit(1);
+            }
+            println!("No problems found");
+        }
+        Some(Command::Migrate) => {
+            let store = ZettelStore::new(&config.db);
+            match store.migrate() {
+                Ok(count) => println!("Migrated {} Zettels", count),
+                Err((id, err)) => {
+                    eprintln!("Can't migrate, as Zettel {} can't be read: {:?}", id.0, err);
+                    std::process::exit(1);
+                }
+            }
+
+            /*
+             * Migrating can change Zettels' tags, so the search index needs to catch up.
+             */
+            if let Some(index) = open_index_on_disk(&config, &store) {
+                index.rebuild(store.all());
+            }
+        }
+        Some(Command::Stats) => {
+            let store = ZettelStore::new(&config.db);
+            admin::print_stats(&store, count_index_on_disk(&config, &store));
+        }
+        Some(Command::Import { dir, owner }) => {
+            let store = ZettelStore::new(&config.db);
+
+            /*
+             * Without an owner, the imported Zettels would be like ones from before there were users, which everyone
+             * can see. That's fine until someone has an account, but after that it's almost never what's wanted.
+             */
+            let users = UserStore::new(&store);
+            let permissions = match owner {
+                Some(owner) if users.get(&owner).is_none() => {
+                    eprintln!("No user called {}", owner);
+                    std::process::exit(1);
+                }
+                Some(owner) => {
+                    Permissions { owner: Some(owner), workspace: None, visibility: Visibility::Private }
+                }
+                None if !users.is_empty() => {
+                    eprintln!("There are users, so the imported Zettels need an owner (--owner)");
+                    std::process::exit(1);
+                }
+                None => Permissions::default(),
+            };
+
+            let index = Index::new(&config.index, config.writer_heap_bytes, store.is_encrypted());
+            let imported = or_exit(import::import_vault(&store, &index, &dir, &permissions));
+            if index.needs_rebuild || store.filled_tag_index() {
+                index.rebuild(store.all());
+            }
+            println!("Imported {} Zettels", imported.len());
+        }
+        Some(Command::Export { file }) => {
+            let store = ZettelStore::new(&config.db);
+            let zettels = store.all();
+            let file = File::create(file).unwrap();
+            export::write_archive(&zettels, BufWriter::new(file)).unwrap().flush().unwrap();
+            println!("Exported {} Zettels", zettels.len());
+        }
+        Some(Command::Backup { file }) => {
+            let store = ZettelStore::new(&config.db);
+            let file = File::create(file).unwrap();
+            let counts = backup::backup(&store, BufWriter::new(file)).unwrap();
+            for (tree, count) in counts {
+                println!("Backed up {} entries from {}", count, tree);
+            }
+        }
+        Some(Command::Restore { file }) => {
+            let store = ZettelStore::new(&config.db);
+            let file = File::open(file).unwrap();
+            match backup::restore(&store, BufReader::new(file)) {
+                Ok(counts) => {
+                    for (tree, count) in counts {
+                        println!("Restored {} entries to {}", count, tree);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Failed to restore backup: {}", err);
+                    std::process::exit(1);
+                }
+            }
+
+            /*
+             * The search index isn't part of the backup, so it needs to be rebuilt from the restored Zettels.
+             */
+            Index::new(&config.index, config.writer_heap_bytes, store.is_encrypted()).rebuild(store.all());
+        }
+        Some(Command::Publish { dir, tags }) => {
+            let store = ZettelStore::new(&config.db);
+
+            /*
+             * With no tags given, everything is published. Otherwise, only Zettels with at least one of the tags (or
+             * a tag nested beneath one of them) are.
+             */
+            let zettels = if tags.is_empty() {
+                store.all()
+            } else {
+                let mut ids = BTreeSet::new();
+                for tag in &tags {
+                    let Some(tag) = tags::normalize(tag) else {
+                        eprintln!("Invalid tag: {}", tag);
+                        std::process::exit(1);
+                    };
+                    ids.extend(store.tagged_within(&tag).into_iter().map(|(_, id)| id));
+                }
+                ids.into_iter().filter_map(|id| Some((id, store.get(id)?))).collect::<Vec<_>>()
+            };
+
+            /*
+             * The published site can be read by anyone, so only Zettels that everyone can already see go on it.
+             */
+            let (zettels, skipped): (Vec<_>, Vec<_>) =
+                zettels.into_iter().partition(|(_, record)| record.permissions.is_public());
+
+            publish::publish(&zettels, &dir).unwrap();
+            println!("Published {} Zettels", zettels.len());
+            if !skipped.is_empty() {
+                println!("Skipped {} Zettels that aren't public", skipped.len());
+            }
+        }
+        Some(Command::List { client }) => {
+            let client = or_exit(Client::open(client, &config));
+            or_exit(terminal::list(&client).await);
+        }
+        Some(Command::Search { query, tags, client }) => {
+            let client = or_exit(Client::open(client, &config));
+            or_exit(terminal::search(&client, &query, &tags).await);
+        }
+        Some(Command::Edit { id, force, client }) => {
+            let client = or_exit(Client::open(client, &config));
+            or_exit(terminal::edit(&client, ZettelId(id), force).await);
+        }
+    }
+}
+
+/// Get the result of a command (like one of the terminal client's), or print the error and exit if it failed.
+fn or_exit<T>(result: std::io::Result<T>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    })
+}
+
+/// Open the search index, unless Zettels are encrypted - then it's only kept in memory while the server is running.
+fn open_index_on_disk(config: &Config, store: &ZettelStore) -> Option<Arc<Index>> {
+    (!store.is_encrypted()).then(|| Index::new(&config.index, config.writer_heap_bytes, false))
+}
+
+/// Count the Zettels in the search index without changing it (see `index::count_on_disk`), unless Zettels are
+/// encrypted and it's only kept in memory.
+fn count_index_on_disk(config: &Config, store: &ZettelStore) -> Option<Result<u64, String>> {
+    (!store.is_encrypted()).then(|| index::count_on_disk(&config.index))
+}
+
+async fn serve(config: Config) {
+    let store = ZettelStore::new(&config.db);
+    let index = Index::new(&config.index, config.writer_heap_bytes, store.is_encrypted());
+    if index.needs_rebuild || store.filled_tag_index() {
+        index.rebuild(store.all());
+    }
+    tokio::spawn(index::commit_index(index.clone(), config.commit_interval));
 
-    let state = Arc::new(AppState { store: ZettelStore::new(), index });
+    let snapshots = Snapshots::new(SnapshotConfig::from_env(config.snapshot_dir.clone()));
+    snapshot::warn_if_unencrypted(&store, &snapshots.config);
+    let users = UserStore::new(&store);
+    let workspaces = WorkspaceStore::new(&store);
+    let audit = AuditLog::new(&store);
+    let limits = RateLimiter::new(LimitsConfig::from_env());
+    let oidc = OidcConfig::from_env().map(Oidc::new);
+    if let Some(oidc) = &oidc {
+        tracing::info!("Single sign-on enabled with {}", oidc.config.issuer);
+    }
+    let state = Arc::new(AppState { store, index, snapshots, users, workspaces, audit, limits, oidc });
+    tokio::spawn(snapshot::take_snapshots(state.clone()));
 
     let api_routes = Router::new()
         .route("/zettel.create", post(zettel::create))
@@ -39,23 +360,68 @@ pub async fn main() {
         .route("/zettel.list", get(zettel::list))
         .route("/zettel.search", get(zettel::search))
         .route("/zettel.update/:id", post(zettel::update))
-        .fallback(api_fallback);
+        .route("/zettel.export/:id", get(zettel::export))
+        .route("/zettel.import", post(zettel::import))
+        .route("/zettel.share/:id", post(share::share))
+        .route("/zettel.unshare/:id", post(share::unshare))
+        .route("/zettel.permissions/:id", post(workspace::set_permissions))
+        .route("/export", get(export::export))
+        .route("/print", get(print::print))
+        .route("/snapshots.status", get(snapshot::status))
+        .route("/graph", get(graph::graph))
+        .route("/graph.orphans", get(graph::orphans))
+        .route("/graph.hubs", get(graph::hubs))
+        .route("/graph.components", get(graph::components))
+        .route("/graph.broken", get(graph::broken))
+        .route("/graph.path", get(graph::path))
+        .route("/zettel.related/:id", get(graph::related))
+        .route("/tags.list", get(tags::list))
+        .route("/tags.zettels/*tag", get(tags::zettels))
+        .route("/tags.rename", post(tags::rename))
+        .route("/tags.merge", post(tags::merge))
+        .route("/auth.me", get(auth::me))
+        .route("/users.create", post(auth::create_user))
+        .route("/audit", get(audit::query))
+        .route("/tokens.create", post(tokens::create))
+        .route("/tokens.list", get(tokens::list))
+        .route("/tokens.revoke/:id", post(tokens::revoke))
+        .route("/workspaces.list", get(workspace::list))
+        .route("/workspaces.create", post(workspace::create))
+        .route("/workspaces.add_member/:name", post(workspace::add_member))
+        .route("/workspaces.remove_member/:name", post(workspace::remove_member))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_user))
+        /*
+         * These need to work without being logged in, so are added after the authentication layer.
+         */
+        .route("/auth.status", get(auth::status))
+        .route("/auth.setup", post(auth::setup))
+        .route("/auth.login", post(auth::login))
+        .route("/auth.logout", post(auth::logout))
+        .fallback(api_fallback)
+        .layer(DefaultBodyLimit::max(state.limits.config.max_body_bytes))
+        .layer(middleware::from_fn_with_state(state.clone(), limits::rate_limit));
 
     /*
      * The main router for the app. We serve static files and route API calls, then fallback to the
      * index page to allow client-side routing to work properly.
      */
-    let dist_dir = option_env!("COMMONPLACE_DIST_DIR").unwrap_or("app/dist/");
+    let frontend = Router::new()
+        .fallback_service(ServeFile::new(config.dist_dir.join("index.html")))
+        .layer(middleware::from_fn_with_state(state.clone(), auth::redirect_to_login));
     let app = Router::new()
-        .nest_service("/static", ServeDir::new(dist_dir))
+        .nest_service("/static", ServeDir::new(&config.dist_dir))
         .nest("/api", api_routes)
-        .fallback_service(ServeFile::new(format!("{}/index.html", dist_dir)))
+        .route("/shared/:token", get(share::shared))
+        .route("/login", get(auth::login_page))
+        .route("/login/oidc", get(oidc::login))
+        .route("/login/oidc/callback", get(oidc::callback))
+        .fallback_service(frontend)
         .with_state(state)
         .layer(TraceLayer::new_for_http());
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(config.bind).await.unwrap();
     tracing::info!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
 
 async fn api_fallback() -> (StatusCode, &'static str) {
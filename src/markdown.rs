@@ -0,0 +1,904 @@
+//! Conversion between `ZettelContent` and Markdown. We target CommonMark, along with the GitHub Flavored Markdown
+//! extensions for tables, task lists, and strikethrough. Things that neither can express (e.g. highlights and
+//! collapsible details) are rendered as inline HTML, which most Markdown renderers will pass through, and which we
+//! understand when parsing Markdown back in.
+
+use crate::zettel::{
+    Block,
+    CodeBlockAttrs,
+    DetailsAttrs,
+    HeadingAttrs,
+    ImageAttrs,
+    Inline,
+    LinkAttrs,
+    Mark,
+    TableAttrs,
+    TaskItemAttrs,
+    ZettelContent,
+    ZettelLinkAttrs,
+};
+use commonplace::ZettelId;
+use pulldown_cmark::{CodeBlockKind, Event, LinkType, Options, Parser as MarkdownParser, Tag, TagEnd};
+use std::collections::{BTreeSet, HashMap};
+
+/// Render a Zettel to Markdown, with its title as a top-level heading. `link` is used to render links to other
+/// Zettels, as how they should be rendered depends on where the Markdown is going (see `wiki_link`).
+pub fn render(title: &str, content: &ZettelContent, link: &dyn Fn(ZettelId) -> String) -> String {
+    let mut result = String::new();
+
+    if !title.is_empty() {
+        result.push_str("# ");
+        result.push_str(&escape(title));
+        result.push_str("\n\n");
+    }
+
+    match content {
+        ZettelContent::Doc { content } => result.push_str(&render_blocks(content, link, "\n\n")),
+    }
+
+    if !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+/// Render a link to another Zettel as a wiki-style link, as used by tools like Obsidian and Logseq.
+pub fn wiki_link(id: ZettelId, title: Option<&str>) -> String {
+    match title {
+        Some(title) if !title.is_empty() => {
+            format!("[[{}|{}]]", id.0, title.replace("]]", "] ]").replace('|', "/"))
+        }
+        _ => format!("[[{}]]", id.0),
+    }
+}
+
+fn render_blocks(blocks: &[Block], link: &dyn Fn(ZettelId) -> String, separator: &str) -> String {
+    blocks.iter().map(|block| render_block(block, link)).collect::<Vec<_>>().join(separator)
+}
+
+fn render_block(block: &Block, link: &dyn Fn(ZettelId) -> String) -> String {
+    match block {
+        Block::Paragraph { content } => escape_block_start(&render_inlines(content.as_deref(), link)),
+        Block::Heading { attrs, content } => {
+            format!("{} {}", "#".repeat(attrs.level.clamp(1, 6)), render_inlines(content.as_deref(), link))
+        }
+        Block::CodeBlock { attrs, content } => {
+            let code = content
+                .iter()
+                .flatten()
+                .map(|inline| match inline {
+                    Inline::Text { text, .. } => text.as_str(),
+                    Inline::ZettelLink { .. } => "",
+                })
+                .collect::<String>();
+
+            /*
+             * The fence has to be longer than any run of backticks in the code itself.
+             */
+            let fence = "`".repeat(longest_run(&code, '`').max(2) + 1);
+            format!(
+                "{}{}\n{}\n{}",
+                fence,
+                attrs.language.as_deref().unwrap_or(""),
+                code.trim_end_matches('\n'),
+                fence
+            )
+        }
+        Block::Blockquote { content } => {
+            prefix_lines(&render_blocks(content.as_deref().unwrap_or(&[]), link, "\n\n"), "> ", "> ")
+        }
+        Block::BulletList { content } | Block::TaskList { content } => {
+            content.iter().flatten().map(|item| render_list_item(item, "- ", link)).collect::<Vec<_>>().join("\n")
+        }
+        Block::OrderedList { content } => content
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(i, item)| render_list_item(item, &format!("{}. ", i + 1), link))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Block::ListItem { .. } | Block::TaskItem { .. } => render_list_item(block, "- ", link),
+        Block::HorizontalRule => "---".to_string(),
+        Block::Table { content } => render_table(content.as_deref().unwrap_or(&[]), link),
+        Block::TableRow { .. } | Block::TableHeader { .. } | Block::TableCell { .. } => {
+            render_table(std::slice::from_ref(block), link)
+        }
+        Block::Image { attrs } => {
+            let title = match &attrs.title {
+                Some(title) => format!(" \"{}\"", title.replace('"', "\\\"")),
+                None => String::new(),
+            };
+            format!(
+                "![{}]({}{})",
+                escape(attrs.alt.as_deref().unwrap_or("")),
+                attrs.src.replace(' ', "%20"),
+                title
+            )
+        }
+        Block::Details { attrs, content } => {
+            let mut result = String::from(if attrs.open { "<details open>\n" } else { "<details>\n" });
+            for child in content.iter().flatten() {
+                match child {
+                    Block::DetailsSummary { content } => result.push_str(&format!(
+                        "<summary>{}</summary>\n\n",
+                        escape_html(&plain_text(content.as_deref().unwrap_or(&[])))
+                    )),
+                    other => {
+                        result.push_str(&render_block(other, link));
+                        result.push_str("\n\n");
+                    }
+                }
+            }
+            result.push_str("</details>");
+            result
+        }
+        Block::DetailsSummary { content } => render_inlines(content.as_deref(), link),
+        Block::DetailsContent { content } => render_blocks(content.as_deref().unwrap_or(&[]), link, "\n\n"),
+    }
+}
+
+fn render_list_item(item: &Block, marker: &str, link: &dyn Fn(ZettelId) -> String) -> String {
+    let (marker, children) = match item {
+        Block::TaskItem { attrs, content } => {
+            (format!("{}[{}] ", marker, if attrs.checked { "x" } else { " " }), content.as_deref().unwrap_or(&[]))
+        }
+        Block::ListItem { content } => (marker.to_string(), content.as_deref().unwrap_or(&[])),
+        other => (marker.to_string(), std::slice::from_ref(other)),
+    };
+
+    /*
+     * Lists are rendered tightly, unless an item contains multiple paragraphs, which need to be separated by a blank
+     * line to stop them being merged together. The same goes for anything after a details block, which would
+     * otherwise be swallowed up by its closing HTML.
+     */
+    let mut body = String::new();
+    for (i, child) in children.iter().enumerate() {
+        if i > 0 {
+            let separate = matches!(
+                (&children[i - 1], child),
+                (Block::Paragraph { .. }, Block::Paragraph { .. }) | (Block::Details { .. }, _)
+            );
+            body.push_str(if separate { "\n\n" } else { "\n" });
+        }
+        body.push_str(&render_block(child, link));
+    }
+
+    prefix_lines(&body, &marker, &" ".repeat(marker.len()))
+}
+
+fn render_table(rows: &[Block], link: &dyn Fn(ZettelId) -> String) -> String {
+    let rows = rows
+        .iter()
+        .map(|row| {
+            let cells = match row {
+                Block::TableRow { content } => content.as_deref().unwrap_or(&[]),
+                other => std::slice::from_ref(other),
+            };
+
+            /*
+             * GFM tables can't express cells that span multiple columns or rows. We pad spanning cells out with
+             * empty cells so at least the columns line up.
+             */
+            let mut rendered = Vec::new();
+            for cell in cells {
+                let (attrs, content) = match cell {
+                    Block::TableHeader { attrs, content } | Block::TableCell { attrs, content } => {
+                        (Some(attrs), content.as_deref().unwrap_or(&[]))
+                    }
+                    other => (None, std::slice::from_ref(other)),
+                };
+
+                let text = render_blocks(content, link, "<br>")
+                    .replace("\\\n", "<br>")
+                    .replace('\n', " ")
+                    .replace('|', "\\|");
+                rendered.push(text);
+                for _ in 1..attrs.map_or(1, |attrs| attrs.colspan) {
+                    rendered.push(String::new());
+                }
+            }
+            rendered
+        })
+        .collect::<Vec<_>>();
+
+    let columns = rows.iter().map(|row| row.len()).max().unwrap_or(0).max(1);
+    let format_row = |row: &[String]| {
+        let mut cells = row.to_vec();
+        cells.resize(columns, String::new());
+        format!("| {} |", cells.join(" | "))
+    };
+
+    /*
+     * GFM requires every table to have a header row, so the first row is always used as one. Tiptap tables normally
+     * start with a row of header cells anyway.
+     */
+    let mut lines = Vec::new();
+    let mut body = rows.iter();
+    match body.next() {
+        Some(first) => lines.push(format_row(first)),
+        None => lines.push(format_row(&[])),
+    }
+    lines.push(format!("|{}", " --- |".repeat(columns)));
+    for row in body {
+        lines.push(format_row(row));
+    }
+    lines.join("\n")
+}
+
+fn render_inlines(inlines: Option<&[Inline]>, link: &dyn Fn(ZettelId) -> String) -> String {
+    inlines.unwrap_or(&[]).iter().map(|inline| render_inline(inline, link)).collect()
+}
+
+fn render_inline(inline: &Inline, link: &dyn Fn(ZettelId) -> String) -> String {
+    match inline {
+        Inline::ZettelLink { attrs } => link(ZettelId(attrs.target)),
+        /*
+         * Hard breaks are stored as newlines in the text. Each line is rendered on its own (so marks don't span the
+         * break), and joined with a backslash, which keeps the break hard. The line after a break could otherwise
+         * start a new block, so it's escaped like the start of a paragraph. A backslash at the end of a paragraph
+         * is just a backslash though, so a break at the end of the text is written as `<br>` instead.
+         */
+        Inline::Text { text, marks }
+            if text.ends_with('\n') && !marks.iter().flatten().any(|mark| *mark == Mark::Code) =>
+        {
+            let text = &text[..text.len() - 1];
+            if text.is_empty() {
+                return "<br>".to_string();
+            }
+            render_inline(&Inline::Text { text: text.to_string(), marks: marks.clone() }, link) + "<br>"
+        }
+        Inline::Text { text, marks }
+            if text.contains('\n') && !marks.iter().flatten().any(|mark| *mark == Mark::Code) =>
+        {
+            text.split('\n')
+                .enumerate()
+                .map(|(i, line)| {
+                    let line = render_inline(&Inline::Text { text: line.to_string(), marks: marks.clone() }, link);
+                    if i == 0 {
+                        line
+                    } else {
+                        escape_block_start(&line)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\\\n")
+        }
+        Inline::Text { text, marks } => {
+            let marks = marks.as_deref().unwrap_or(&[]);
+
+            let mut rendered = if marks.iter().any(|mark| matches!(mark, Mark::Code)) {
+                let ticks = "`".repeat(longest_run(text, '`') + 1);
+                let padding = if text.starts_with('`') || text.ends_with('`') { " " } else { "" };
+                format!("{}{}{}{}{}", ticks, padding, text, padding, ticks)
+            } else {
+                escape(text)
+            };
+
+            /*
+             * Emphasis can't start or end with whitespace in Markdown, so we move any surrounding whitespace outside
+             * the delimiters.
+             */
+            let leading = rendered.len() - rendered.trim_start().len();
+            let trailing = rendered.len() - rendered.trim_end().len();
+            if leading == rendered.len() {
+                return rendered;
+            }
+            let (before, after) =
+                (rendered[..leading].to_string(), rendered[rendered.len() - trailing..].to_string());
+            rendered = rendered.trim().to_string();
+
+            for mark in marks {
+                rendered = match mark {
+                    Mark::Bold => format!("**{}**", rendered),
+                    Mark::Italic => format!("_{}_", rendered),
+                    Mark::Strike => format!("~~{}~~", rendered),
+                    Mark::Code => rendered,
+                    Mark::Superscript => format!("<sup>{}</sup>", rendered),
+                    Mark::Subscript => format!("<sub>{}</sub>", rendered),
+                    Mark::Highlight { .. } => format!("<mark>{}</mark>", rendered),
+                    Mark::Link { .. } => rendered,
+                };
+            }
+            if let Some(Mark::Link { attrs }) = marks.iter().find(|mark| matches!(mark, Mark::Link { .. })) {
+                rendered = format!("[{}]({})", rendered, attrs.href.replace(' ', "%20").replace(')', "%29"));
+            }
+
+            format!("{}{}{}", before, rendered, after)
+        }
+    }
+}
+
+/// Escape characters in text that would otherwise be interpreted as Markdown syntax (including `&`, which could
+/// start an HTML entity). `#` is left alone, as it's only special at the start of a line (which
+/// `escape_block_start` deals with), and escaping it would break `#hashtags`.
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '~' | '&') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Escape anything at the start of a paragraph (or a line after a hard break) that would make it be parsed as a
+/// different type of block. Leading whitespace would either be dropped or start a code block, so it's written as
+/// character references instead.
+fn escape_block_start(text: &str) -> String {
+    let indent = text.len() - text.trim_start_matches([' ', '\t']).len();
+    if indent > 0 {
+        let references = text[..indent].chars().map(|c| format!("&#{};", c as u32)).collect::<String>();
+        return format!("{}{}", references, &text[indent..]);
+    }
+
+    let digits = text.chars().take_while(|c| c.is_ascii_digit()).count();
+    let after_digits = text[digits..].chars().next();
+
+    let heading = text.starts_with('#') && {
+        let rest = text.trim_start_matches('#');
+        rest.is_empty() || rest.starts_with(' ')
+    };
+    let list = (text.starts_with("- ") || text.starts_with("+ ") || text == "-" || text == "+")
+        || (digits > 0 && matches!(after_digits, Some('.') | Some(')')));
+    let rule = text.len() >= 3 && text.chars().all(|c| c == '-' || c == '=');
+
+    if heading || list || rule {
+        if digits > 0 {
+            format!("{}\\{}", &text[..digits], &text[digits..])
+        } else {
+            format!("\\{}", text)
+        }
+    } else {
+        text.to_string()
+    }
+}
+
+/// Escape text to go in HTML, like the summary of a details block.
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&amp;", "&")
+}
+
+fn prefix_lines(text: &str, first: &str, rest: &str) -> String {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            let prefix = if i == 0 { first } else { rest };
+            if line.is_empty() {
+                prefix.trim_end().to_string()
+            } else {
+                format!("{}{}", prefix, line)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn longest_run(text: &str, c: char) -> usize {
+    let mut longest = 0;
+    let mut current = 0;
+    for next in text.chars() {
+        if next == c {
+            current += 1;
+            longest = longest.max(current);
+        } else {
+            current = 0;
+        }
+    }
+    longest
+}
+
+pub struct Parsed {
+    /// The text of the first heading, if the document starts with a top-level heading.
+    pub title: Option<String>,
+    pub content: ZettelContent,
+}
+
+/// Resolve the targets of wiki-style links to one of `zettels`, by either its ID or its title (ignoring case).
+pub fn resolver(zettels: &[(ZettelId, String)]) -> impl Fn(&str) -> Option<ZettelId> {
+    let ids = zettels.iter().map(|(id, _)| *id).collect::<BTreeSet<_>>();
+    let titles = zettels.iter().map(|(id, title)| (title.to_lowercase(), *id)).collect::<HashMap<_, _>>();
+    move |target| match target.trim().parse::<u64>() {
+        Ok(id) if ids.contains(&ZettelId(id)) => Some(ZettelId(id)),
+        _ => titles.get(&target.trim().to_lowercase()).copied(),
+    }
+}
+
+/// Parse Markdown into a Zettel. Wiki-style links (`[[target]]` or `[[target|text]]`) are turned into links to
+/// other Zettels if `resolve` can find a Zettel for their target, and are otherwise left as they are.
+pub fn parse(markdown: &str, resolve: &dyn Fn(&str) -> Option<ZettelId>) -> Parsed {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_WIKILINKS
+        | Options::ENABLE_YAML_STYLE_METADATA_BLOCKS;
+
+    let mut parser = Parser::new(resolve);
+    for event in MarkdownParser::new_ext(markdown, options) {
+        parser.event(event);
+    }
+    let mut blocks = parser.finish();
+
+    let title = match blocks.first() {
+        Some(Block::Heading { attrs, content }) if attrs.level == 1 => {
+            let title = plain_text(content.as_deref().unwrap_or(&[]));
+            blocks.remove(0);
+            Some(title)
+        }
+        _ => None,
+    };
+
+    Parsed { title, content: ZettelContent::Doc { content: blocks } }
+}
+
+fn plain_text(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text { text, .. } => text.as_str(),
+            Inline::ZettelLink { .. } => "",
+        })
+        .collect()
+}
+
+/// A node that is still being built while parsing.
+enum Frame {
+    Root,
+    Blockquote,
+    List { ordered: bool },
+    Item { checked: Option<bool> },
+    Table,
+    TableHead,
+    TableRow,
+    TableCell { header: bool },
+    Details { open: bool, summary: Option<Vec<Inline>> },
+    Paragraph,
+    Heading(usize),
+    CodeBlock(Option<String>),
+}
+
+impl Frame {
+    fn holds_text(&self) -> bool {
+        matches!(self, Frame::Paragraph | Frame::Heading(_) | Frame::CodeBlock(_))
+    }
+
+    fn ends_with(&self, tag: &TagEnd) -> bool {
+        matches!(
+            (self, tag),
+            (Frame::Paragraph, TagEnd::Paragraph)
+                | (Frame::Heading(_), TagEnd::Heading(_))
+                | (Frame::Blockquote, TagEnd::BlockQuote(_))
+                | (Frame::CodeBlock(_), TagEnd::CodeBlock)
+                | (Frame::List { .. }, TagEnd::List(_))
+                | (Frame::Item { .. }, TagEnd::Item)
+                | (Frame::Table, TagEnd::Table)
+                | (Frame::TableHead, TagEnd::TableHead)
+                | (Frame::TableRow, TagEnd::TableRow)
+                | (Frame::TableCell { .. }, TagEnd::TableCell)
+        )
+    }
+}
+
+struct Open {
+    frame: Frame,
+    blocks: Vec<Block>,
+    /// For frames that hold text, this is their content. Other frames can also end up with inline content that
+    /// isn't in a paragraph (e.g. in tight lists and in table cells), which we wrap in a paragraph, as tiptap does.
+    inlines: Vec<Inline>,
+}
+
+struct Parser<'a> {
+    resolve: &'a dyn Fn(&str) -> Option<ZettelId>,
+    stack: Vec<Open>,
+    marks: Vec<Mark>,
+    /// The links we're currently inside. Wiki-style links collect their text here, as we don't know what to turn
+    /// them into until we've seen all of it; normal links are represented by a mark instead.
+    links: Vec<Option<(String, String)>>,
+    /// The image we're currently inside, if any. Its text becomes its alt text.
+    image: Option<(String, String, String)>,
+    in_metadata: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new(resolve: &'a dyn Fn(&str) -> Option<ZettelId>) -> Parser<'a> {
+        Parser {
+            resolve,
+            stack: vec![Open { frame: Frame::Root, blocks: Vec::new(), inlines: Vec::new() }],
+            marks: Vec::new(),
+            links: Vec::new(),
+            image: None,
+            in_metadata: false,
+        }
+    }
+
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.text(&text, false),
+            Event::Code(text) => self.text(&text, true),
+            Event::SoftBreak => self.text(" ", false),
+            Event::HardBreak => self.hard_break(),
+            Event::Rule => self.push_block(Block::HorizontalRule),
+            Event::TaskListMarker(checked) => {
+                if let Some(Open { frame: Frame::Item { checked: item }, .. }) =
+                    self.stack.iter_mut().rev().find(|open| matches!(open.frame, Frame::Item { .. }))
+                {
+                    *item = Some(checked);
+                }
+            }
+            Event::InlineHtml(html) => self.inline_html(&html),
+            Event::Html(html) => self.block_html(&html),
+            _ => (),
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph => self.open(Frame::Paragraph),
+            Tag::Heading { level, .. } => self.open(Frame::Heading(level as usize)),
+            Tag::BlockQuote(_) => self.open(Frame::Blockquote),
+            Tag::CodeBlock(kind) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().map(str::to_string),
+                    CodeBlockKind::Indented => None,
+                };
+                self.open(Frame::CodeBlock(language))
+            }
+            Tag::List(start) => self.open(Frame::List { ordered: start.is_some() }),
+            Tag::Item => self.open(Frame::Item { checked: None }),
+            Tag::Table(_) => self.open(Frame::Table),
+            Tag::TableHead => self.open(Frame::TableHead),
+            Tag::TableRow => self.open(Frame::TableRow),
+            Tag::TableCell => {
+                let header = matches!(self.top().frame, Frame::TableHead);
+                self.open(Frame::TableCell { header })
+            }
+            Tag::Emphasis => self.marks.push(Mark::Italic),
+            Tag::Strong => self.marks.push(Mark::Bold),
+            Tag::Strikethrough => self.marks.push(Mark::Strike),
+            Tag::Superscript => self.marks.push(Mark::Superscript),
+            Tag::Subscript => self.marks.push(Mark::Subscript),
+            Tag::Link { link_type: LinkType::WikiLink { .. }, dest_url, .. } => {
+                self.links.push(Some((dest_url.to_string(), String::new())))
+            }
+            Tag::Link { dest_url, .. } => {
+                self.links.push(None);
+                self.marks.push(Mark::Link {
+                    attrs: LinkAttrs {
+                        href: dest_url.to_string(),
+                        target: "_blank".to_string(),
+                        rel: "noopener noreferrer nofollow".to_string(),
+                    },
+                });
+            }
+            Tag::Image { dest_url, title, .. } => {
+                self.image = Some((dest_url.to_string(), title.to_string(), String::new()))
+            }
+            Tag::MetadataBlock(_) => self.in_metadata = true,
+            _ => (),
+        }
+    }
+
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph
+            | TagEnd::Heading(_)
+            | TagEnd::BlockQuote(_)
+            | TagEnd::CodeBlock
+            | TagEnd::List(_)
+            | TagEnd::Item
+            | TagEnd::Table
+            | TagEnd::TableHead
+            | TagEnd::TableRow
+            | TagEnd::TableCell => self.close_until(|frame| frame.ends_with(&tag)),
+            TagEnd::Emphasis => self.pop_mark(|mark| matches!(mark, Mark::Italic)),
+            TagEnd::Strong => self.pop_mark(|mark| matches!(mark, Mark::Bold)),
+            TagEnd::Strikethrough => self.pop_mark(|mark| matches!(mark, Mark::Strike)),
+            TagEnd::Superscript => self.pop_mark(|mark| matches!(mark, Mark::Superscript)),
+            TagEnd::Subscript => self.pop_mark(|mark| matches!(mark, Mark::Subscript)),
+            TagEnd::Link => match self.links.pop() {
+                Some(Some((target, text))) => match (self.resolve)(&target) {
+                    Some(id) => self.push_inline(Inline::ZettelLink { attrs: ZettelLinkAttrs { target: id.0 } }),
+                    None if text.is_empty() || text == target => self.text(&format!("[[{}]]", target), false),
+                    None => self.text(&format!("[[{}|{}]]", target, text), false),
+                },
+                _ => self.pop_mark(|mark| matches!(mark, Mark::Link { .. })),
+            },
+            TagEnd::Image => {
+                if let Some((src, title, alt)) = self.image.take() {
+                    self.push_block(Block::Image {
+                        attrs: ImageAttrs {
+                            src,
+                            alt: if alt.is_empty() { None } else { Some(alt) },
+                            title: if title.is_empty() { None } else { Some(title) },
+                        },
+                    });
+                }
+            }
+            TagEnd::MetadataBlock(_) => self.in_metadata = false,
+            _ => (),
+        }
+    }
+
+    fn text(&mut self, text: &str, code: bool) {
+        if self.in_metadata || text.is_empty() {
+            return;
+        }
+
+        if let Some((_, _, alt)) = &mut self.image {
+            alt.push_str(text);
+            return;
+        }
+        if let Some(Some((_, link_text))) = self.links.last_mut() {
+            link_text.push_str(text);
+            return;
+        }
+
+        let mut marks = self.marks.clone();
+        if code && !matches!(self.top().frame, Frame::CodeBlock(_)) {
+            marks.push(Mark::Code);
+        }
+        self.push_inline(Inline::Text {
+            text: text.to_string(),
+            marks: if marks.is_empty() { None } else { Some(marks) },
+        });
+    }
+
+    /// Hard breaks are rendered after any closing marks (`**a**\`), so they're added to the end of the text before
+    /// them, whatever its marks are, to get back the same text we rendered.
+    fn hard_break(&mut self) {
+        if self.in_metadata || self.image.is_some() || matches!(self.links.last(), Some(Some(_))) {
+            return self.text("\n", false);
+        }
+        if let Some(Inline::Text { text, marks }) = self.top().inlines.last_mut() {
+            if !marks.iter().flatten().any(|mark| *mark == Mark::Code) {
+                text.push('\n');
+                return;
+            }
+        }
+        self.text("\n", false);
+    }
+
+    /// Handle the bits of inline HTML that we produce when rendering Markdown. Anything else is dropped.
+    fn inline_html(&mut self, html: &str) {
+        match html.trim().to_lowercase().as_str() {
+            "<sup>" => self.marks.push(Mark::Superscript),
+            "</sup>" => self.pop_mark(|mark| matches!(mark, Mark::Superscript)),
+            "<sub>" => self.marks.push(Mark::Subscript),
+            "</sub>" => self.pop_mark(|mark| matches!(mark, Mark::Subscript)),
+            "<mark>" => self.marks.push(Mark::Highlight { color: None }),
+            "</mark>" => self.pop_mark(|mark| matches!(mark, Mark::Highlight { .. })),
+            "<br>" | "<br/>" | "<br />" => self.hard_break(),
+            _ => (),
+        }
+    }
+
+    /// Handle HTML blocks. The only ones we understand are `<details>` and `<summary>`, which we use to represent
+    /// collapsible blocks. Anything else is dropped.
+    fn block_html(&mut self, html: &str) {
+        for line in html.lines().map(str::trim) {
+            if line.starts_with("<details") {
+                self.open(Frame::Details { open: line.contains(" open"), summary: None });
+            }
+
+            if let (Some(start), Some(end)) = (line.find("<summary>"), line.find("</summary>")) {
+                let summary = unescape_html(&strip_tags(&line[(start + "<summary>".len())..end]));
+                if let Some(Open { frame: Frame::Details { summary: details_summary, .. }, .. }) =
+                    self.stack.iter_mut().rev().find(|open| matches!(open.frame, Frame::Details { .. }))
+                {
+                    *details_summary = Some(vec![Inline::Text { text: summary, marks: None }]);
+                }
+            }
+
+            /*
+             * A `</details>` only closes the details block if it's in the same container (e.g. the same list item) as
+             * the `<details>`. Otherwise the details block is closed when its container ends.
+             */
+            if line.contains("</details>") && matches!(self.top().frame, Frame::Details { .. }) {
+                self.close();
+            }
+        }
+    }
+
+    fn top(&mut self) -> &mut Open {
+        self.stack.last_mut().unwrap()
+    }
+
+    fn pop_mark(&mut self, is_mark: impl Fn(&Mark) -> bool) {
+        if let Some(index) = self.marks.iter().rposition(is_mark) {
+            self.marks.remove(index);
+        }
+    }
+
+    fn push_inline(&mut self, inline: Inline) {
+        let inlines = &mut self.top().inlines;
+
+        /*
+         * The Markdown parser can split up text in unexpected places, so merge adjacent text with the same marks.
+         */
+        if let (Some(Inline::Text { text: last, marks: last_marks }), Inline::Text { text, marks }) =
+            (inlines.last_mut(), &inline)
+        {
+            if last_marks == marks {
+                last.push_str(text);
+                return;
+            }
+        }
+        inlines.push(inline);
+    }
+
+    /// Add a block to the innermost frame that can hold blocks. If we're in the middle of some text (e.g. an image
+    /// in the middle of a paragraph), the text is split around it.
+    fn push_block(&mut self, block: Block) {
+        if self.top().frame.holds_text() {
+            let open = self.top();
+            let inlines = std::mem::take(&mut open.inlines);
+            if let Some(partial) = build_text_block(&open.frame, inlines) {
+                let parent = self.stack.len() - 2;
+                self.stack[parent].blocks.push(partial);
+            }
+            let parent = self.stack.len() - 2;
+            self.stack[parent].blocks.push(block);
+        } else {
+            flush_loose_text(self.top());
+            self.top().blocks.push(block);
+        }
+    }
+
+    fn open(&mut self, frame: Frame) {
+        if !self.top().frame.holds_text() {
+            flush_loose_text(self.top());
+        }
+        self.stack.push(Open { frame, blocks: Vec::new(), inlines: Vec::new() });
+    }
+
+    /// Close the innermost frame matching the end of a tag, and any details blocks still open inside it. A
+    /// details block can be opened inside a list item or blockquote and not closed before it ends, so the frame on
+    /// top isn't necessarily the one the tag belongs to.
+    fn close_until(&mut self, is_frame: impl Fn(&Frame) -> bool) {
+        let Some(index) = self.stack.iter().rposition(|open| is_frame(&open.frame)) else {
+            return;
+        };
+        if self.stack[index + 1..].iter().all(|open| matches!(open.frame, Frame::Details { .. })) {
+            while self.stack.len() > index {
+                self.close();
+            }
+        }
+    }
+
+    fn close(&mut self) {
+        if self.stack.len() == 1 {
+            return;
+        }
+
+        let mut open = self.stack.pop().unwrap();
+        if !open.frame.holds_text() {
+            flush_loose_text(&mut open);
+        }
+
+        let block = match open.frame {
+            Frame::Root => unreachable!(),
+            Frame::Paragraph | Frame::Heading(_) | Frame::CodeBlock(_) => {
+                build_text_block(&open.frame, open.inlines)
+            }
+            Frame::Blockquote => Some(Block::Blockquote { content: Some(open.blocks) }),
+            Frame::List { ordered } => {
+                let is_task_list = open.blocks.iter().any(|item| matches!(item, Block::TaskItem { .. }));
+                if is_task_list {
+                    let items = open
+                        .blocks
+                        .into_iter()
+                        .map(|item| match item {
+                            Block::ListItem { content } => {
+                                Block::TaskItem { attrs: TaskItemAttrs { checked: false }, content }
+                            }
+                            other => other,
+                        })
+                        .collect();
+                    Some(Block::TaskList { content: Some(items) })
+                } else if ordered {
+                    Some(Block::OrderedList { content: Some(open.blocks) })
+                } else {
+                    Some(Block::BulletList { content: Some(open.blocks) })
+                }
+            }
+            Frame::Item { checked } => {
+                let content = Some(non_empty(open.blocks));
+                match checked {
+                    Some(checked) => Some(Block::TaskItem { attrs: TaskItemAttrs { checked }, content }),
+                    None => Some(Block::ListItem { content }),
+                }
+            }
+            Frame::Table => Some(Block::Table { content: Some(open.blocks) }),
+            Frame::TableHead | Frame::TableRow => Some(Block::TableRow { content: Some(open.blocks) }),
+            Frame::TableCell { header } => {
+                let attrs = TableAttrs { colspan: 1, rowspan: 1, colwidth: None };
+                let content = Some(non_empty(open.blocks));
+                if header {
+                    Some(Block::TableHeader { attrs, content })
+                } else {
+                    Some(Block::TableCell { attrs, content })
+                }
+            }
+            Frame::Details { open: is_open, summary } => Some(Block::Details {
+                attrs: DetailsAttrs { open: is_open },
+                content: Some(vec![
+                    Block::DetailsSummary { content: summary },
+                    Block::DetailsContent { content: Some(non_empty(open.blocks)) },
+                ]),
+            }),
+        };
+
+        if let Some(block) = block {
+            self.top().blocks.push(block);
+        }
+    }
+
+    fn finish(mut self) -> Vec<Block> {
+        while self.stack.len() > 1 {
+            self.close();
+        }
+        let mut root = self.stack.pop().unwrap();
+        flush_loose_text(&mut root);
+        root.blocks
+    }
+}
+
+fn build_text_block(frame: &Frame, inlines: Vec<Inline>) -> Option<Block> {
+    match frame {
+        Frame::Paragraph if inlines.is_empty() => None,
+        Frame::Paragraph => Some(Block::Paragraph { content: Some(inlines) }),
+        Frame::Heading(level) => Some(Block::Heading {
+            attrs: HeadingAttrs { level: *level },
+            content: if inlines.is_empty() { None } else { Some(inlines) },
+        }),
+        Frame::CodeBlock(language) => {
+            let code = plain_text(&inlines);
+            let code = code.strip_suffix('\n').unwrap_or(&code);
+            Some(Block::CodeBlock {
+                attrs: CodeBlockAttrs { language: language.clone() },
+                content: if code.is_empty() {
+                    None
+                } else {
+                    Some(vec![Inline::Text { text: code.to_string(), marks: None }])
+                },
+            })
+        }
+        _ => None,
+    }
+}
+
+fn flush_loose_text(open: &mut Open) {
+    let inlines = std::mem::take(&mut open.inlines);
+    if inlines.iter().any(|inline| !matches!(inline, Inline::Text { text, .. } if text.trim().is_empty())) {
+        open.blocks.push(Block::Paragraph { content: Some(inlines) });
+    }
+}
+
+/// Tiptap expects blocks like list items and table cells to contain at least one paragraph.
+fn non_empty(blocks: Vec<Block>) -> Vec<Block> {
+    if blocks.is_empty() {
+        vec![Block::Paragraph { content: None }]
+    } else {
+        blocks
+    }
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut result = String::new();
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => result.push(c),
+            _ => (),
+        }
+    }
+    result
+}
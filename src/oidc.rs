@@ -0,0 +1,351 @@
+//! Logging in with an OpenID Connect single sign-on provider, using the authorization code flow with PKCE. The
+//! provider's endpoints are discovered from its issuer URL, and the ID token it returns is checked against its
+//! published keys (or the client secret, for providers that sign tokens with it).
+//!
+//! The first time someone logs in, their identity with the provider is linked to a Commonplace user, which is
+//! created for them if it doesn't exist yet. After that, they're always logged in as that user, even if their
+//! username with the provider changes.
+//!
+//! This is configured through environment variables, and is turned off unless an issuer is set:
+//!    - `COMMONPLACE_OIDC_ISSUER` - the provider's issuer URL
+//!    - `COMMONPLACE_OIDC_CLIENT_ID` - the client ID Commonplace is registered with
+//!    - `COMMONPLACE_OIDC_CLIENT_SECRET` - the client secret, if the provider gave us one
+//!    - `COMMONPLACE_OIDC_REDIRECT_URL` - where the provider sends people back to after they log in. This should
+//!      be `/login/oidc/callback` on whatever URL Commonplace is served at.
+//!    - `COMMONPLACE_OIDC_USERNAME_CLAIM` - the claim new users' usernames are taken from (default
+//!      `preferred_username`)
+//!    - `COMMONPLACE_OIDC_LINK_EXISTING` - whether to link someone to an existing user with the same username, rather
+//!      than refusing to log them in (default `false`). Only turn this on if the provider's usernames can be
+//!      trusted to belong to the same people as the existing users.
+
+use crate::{
+    audit::Action,
+    auth::{self, is_valid_username},
+    store::random_token,
+    AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{AppendHeaders, IntoResponse, Redirect},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+use tracing::{error, info, warn};
+
+const STATE_COOKIE: &str = "commonplace_oidc_state";
+
+/// How long someone has to log in with the provider before they have to start again, in minutes.
+const LOGIN_MINUTES: i64 = 10;
+
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub username_claim: String,
+    pub link_existing: bool,
+}
+
+impl OidcConfig {
+    pub fn from_env() -> Option<OidcConfig> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let issuer = var("COMMONPLACE_OIDC_ISSUER")?;
+        let required = |name: &str| {
+            var(name).unwrap_or_else(|| panic!("{} should be set when COMMONPLACE_OIDC_ISSUER is", name))
+        };
+
+        Some(OidcConfig {
+            issuer,
+            client_id: required("COMMONPLACE_OIDC_CLIENT_ID"),
+            client_secret: var("COMMONPLACE_OIDC_CLIENT_SECRET"),
+            redirect_url: required("COMMONPLACE_OIDC_REDIRECT_URL"),
+            username_claim: var("COMMONPLACE_OIDC_USERNAME_CLAIM")
+                .unwrap_or_else(|| "preferred_username".to_string()),
+            link_existing: var("COMMONPLACE_OIDC_LINK_EXISTING")
+                .map(|link| link.parse::<bool>().expect("COMMONPLACE_OIDC_LINK_EXISTING should be true or false"))
+                .unwrap_or(false),
+        })
+    }
+}
+
+pub struct Oidc {
+    pub config: OidcConfig,
+    client: reqwest::Client,
+    /// The provider's configuration. This is discovered the first time someone logs in, rather than when the server
+    /// starts, so Commonplace still starts if the provider is down.
+    provider: OnceCell<ProviderMetadata>,
+    /// Logins that have been started but not finished yet, keyed by their `state` parameter.
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Clone, Debug)]
+struct PendingLogin {
+    nonce: String,
+    /// The PKCE code verifier, which proves to the provider that we're the ones who started the login.
+    verifier: String,
+    expires: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    nonce: Option<String>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Oidc {
+        let client = reqwest::Client::builder().timeout(std::time::Duration::from_secs(10)).build().unwrap();
+        Oidc { config, client, provider: OnceCell::new(), pending: Mutex::new(HashMap::new()) }
+    }
+
+    async fn provider(&self) -> Result<&ProviderMetadata, StatusCode> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+                let metadata: ProviderMetadata = self.get_json(&url).await?;
+                if metadata.issuer != self.config.issuer {
+                    return Err(provider_error(format!(
+                        "Provider's issuer {} doesn't match the configured issuer {}",
+                        metadata.issuer, self.config.issuer
+                    )));
+                }
+                Ok(metadata)
+            })
+            .await
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, StatusCode> {
+        let response = self.client.get(url).send().await.map_err(provider_error)?;
+        response.error_for_status().map_err(provider_error)?.json().await.map_err(provider_error)
+    }
+
+    /// Exchange an authorization code for an ID token.
+    async fn exchange(&self, code: &str, verifier: &str) -> Result<String, StatusCode> {
+        let provider = self.provider().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response =
+            self.client.post(&provider.token_endpoint).form(&form).send().await.map_err(provider_error)?;
+        let response: TokenResponse =
+            response.error_for_status().map_err(provider_error)?.json().await.map_err(provider_error)?;
+        Ok(response.id_token)
+    }
+
+    /// Check an ID token's signature, that it was issued to us by the provider, and that it's for the login we
+    /// started, returning its claims.
+    async fn verify(&self, id_token: &str, nonce: &str) -> Result<Claims, StatusCode> {
+        let provider = self.provider().await?;
+        let header = jsonwebtoken::decode_header(id_token).map_err(invalid_token)?;
+
+        /*
+         * Tokens signed with an HMAC use the client secret as the key. Without a secret, anyone could sign one, so
+         * we only accept them if we have one.
+         */
+        let key = match (header.alg, &self.config.client_secret) {
+            (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, Some(secret)) => {
+                DecodingKey::from_secret(secret.as_bytes())
+            }
+            (Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512, None) => {
+                return Err(invalid_token("Token is signed with the client secret, but there isn't one"));
+            }
+            _ => {
+                let keys: JwkSet = self.get_json(&provider.jwks_uri).await?;
+                let key = match &header.kid {
+                    Some(kid) => keys.find(kid),
+                    None => keys.keys.first(),
+                };
+                DecodingKey::from_jwk(key.ok_or_else(|| invalid_token("Token's key isn't published"))?)
+                    .map_err(invalid_token)?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = jsonwebtoken::decode::<Claims>(id_token, &key, &validation).map_err(invalid_token)?.claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(invalid_token("Token's nonce doesn't match"));
+        }
+        Ok(claims)
+    }
+}
+
+fn provider_error(err: impl Debug) -> StatusCode {
+    error!("Single sign-on provider request failed: {:?}", err);
+    StatusCode::BAD_GATEWAY
+}
+
+fn invalid_token(err: impl Debug) -> StatusCode {
+    warn!("Rejected single sign-on ID token: {:?}", err);
+    StatusCode::UNAUTHORIZED
+}
+
+/// Start logging in, by sending the user to the provider.
+pub async fn login(State(state): State<Arc<AppState>>) -> Result<impl IntoResponse, StatusCode> {
+    let oidc = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let provider = oidc.provider().await?;
+
+    let login_state = random_token();
+    let nonce = random_token();
+    let verifier = format!("{}{}", random_token(), random_token());
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+    let url = reqwest::Url::parse_with_params(
+        &provider.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &oidc.config.client_id),
+            ("redirect_uri", &oidc.config.redirect_url),
+            ("scope", "openid profile email"),
+            ("state", &login_state),
+            ("nonce", &nonce),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(provider_error)?;
+
+    let mut pending = oidc.pending.lock().unwrap();
+    let now = Utc::now();
+    pending.retain(|_, login| login.expires > now);
+    pending.insert(
+        login_state.clone(),
+        PendingLogin { nonce, verifier, expires: now + Duration::minutes(LOGIN_MINUTES) },
+    );
+
+    /*
+     * The state is also put in a cookie, so a login can only be finished in the browser that started it. Otherwise,
+     * someone could get a victim to finish a login that they started, and log the victim in as them.
+     */
+    let cookie = format!(
+        "{}={}; HttpOnly; SameSite=Lax; Path=/login/oidc; Max-Age={}",
+        STATE_COOKIE,
+        login_state,
+        Duration::minutes(LOGIN_MINUTES).num_seconds()
+    );
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to(url.as_str())))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    /// If the login failed (or the user cancelled it), the provider sends back an error instead of a code.
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Finish logging in, when the provider sends the user back to us.
+pub async fn callback(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<CallbackParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let oidc = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    if let Some(error) = &params.error {
+        warn!("Single sign-on failed: {} {}", error, params.error_description.as_deref().unwrap_or_default());
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let (Some(code), Some(login_state)) = (params.code, params.state) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    if auth::cookie(&headers, STATE_COOKIE).as_ref() != Some(&login_state) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let pending = oidc.pending.lock().unwrap().remove(&login_state);
+    let pending = pending.filter(|pending| pending.expires > Utc::now()).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let id_token = oidc.exchange(&code, &pending.verifier).await?;
+    let claims = oidc.verify(&id_token, &pending.nonce).await?;
+    let username = user_for(&state, oidc, &claims).await?;
+
+    let token = state.users.start_session(&username);
+    let clear_state = format!("{}=; HttpOnly; SameSite=Lax; Path=/login/oidc; Max-Age=0", STATE_COOKIE);
+    Ok((
+        AppendHeaders([(header::SET_COOKIE, auth::session_cookie(&token)), (header::SET_COOKIE, clear_state)]),
+        Redirect::to("/"),
+    ))
+}
+
+/// Find the user someone's identity is linked to, linking it to a new user (or, if allowed, an existing one) if
+/// this is the first time they've logged in.
+async fn user_for(state: &Arc<AppState>, oidc: &Oidc, claims: &Claims) -> Result<String, StatusCode> {
+    let issuer = &oidc.provider().await?.issuer;
+    if let Some(username) = state.users.identity(issuer, &claims.sub) {
+        return Ok(username);
+    }
+
+    let username = claims
+        .other
+        .get(&oidc.config.username_claim)
+        .and_then(|username| username.as_str())
+        .filter(|username| is_valid_username(username))
+        .ok_or_else(|| {
+            warn!(
+                "Single sign-on identity {} doesn't have a usable {} claim",
+                claims.sub, oidc.config.username_claim
+            );
+            StatusCode::FORBIDDEN
+        })?;
+
+    let (new_user, detail) =
+        (username.to_string(), format!("{} (single sign-on identity {})", username, claims.sub));
+    let created = state
+        .blocking(move |state| {
+            let created = state.users.create_without_password(&new_user);
+            if created {
+                state.audit.record(&new_user, Action::CreateUser, None, Some(detail));
+            }
+            created
+        })
+        .await;
+    if created {
+        info!("Created user {} for single sign-on identity {}", username, claims.sub);
+    } else if oidc.config.link_existing {
+        info!("Linked existing user {} to single sign-on identity {}", username, claims.sub);
+    } else {
+        warn!("Single sign-on identity {} would be linked to existing user {}", claims.sub, username);
+        return Err(StatusCode::CONFLICT);
+    }
+
+    state.users.link_identity(issuer, &claims.sub, username);
+    Ok(username.to_string())
+}
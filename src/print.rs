@@ -0,0 +1,186 @@
+//! Print-ready documents containing one or more Zettels. These are single, standalone HTML files that can be read
+//! offline or printed (including to a PDF) from a browser. They start with a table of contents built from the
+//! Zettels' headings, and any Zettels they link to are transcluded at the end, so the document makes sense without
+//! access to the rest of the Zettelkasten.
+
+use crate::{
+    html::{self, Heading},
+    store::ZettelRecord,
+    tags,
+    workspace::Access,
+    AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Extension,
+};
+use commonplace::ZettelId;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PrintParams {
+    /// A comma-separated list of the IDs of Zettels to include.
+    ids: Option<String>,
+    /// Include every Zettel with this tag, or a tag nested beneath it.
+    tag: Option<String>,
+    /// The title of the document. Defaults to the title of the Zettel if there's only one.
+    title: Option<String>,
+    /// Whether to transclude Zettels that the included Zettels link to. Defaults to `true`.
+    transclude: Option<bool>,
+}
+
+pub async fn print(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Query(params): Query<PrintParams>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let mut ids = Vec::new();
+    if let Some(requested) = &params.ids {
+        for id in requested.split(',').filter(|id| !id.trim().is_empty()) {
+            ids.push(ZettelId(id.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?));
+        }
+    }
+    if let Some(tag) = &params.tag {
+        let tag = tags::normalize(tag).ok_or(StatusCode::BAD_REQUEST)?;
+        let mut tagged = state.store.tagged_within(&tag).into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+        tagged.retain(|id| access.read(state.store.get(*id)).is_ok());
+        tagged.sort();
+        ids.extend(tagged);
+    }
+
+    let mut seen = BTreeSet::new();
+    ids.retain(|id| seen.insert(*id));
+    let zettels = ids
+        .into_iter()
+        .map(|id| Ok((id, access.read(state.store.get(id))?)))
+        .collect::<Result<Vec<_>, StatusCode>>()?;
+    if zettels.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let transcluded = if params.transclude.unwrap_or(true) {
+        let mut linked = BTreeSet::new();
+        for (_, record) in &zettels {
+            linked.extend(record.content.links().into_iter().filter(|target| !seen.contains(target)));
+        }
+        linked.into_iter().filter_map(|id| Some((id, access.read(state.store.get(id)).ok()?))).collect()
+    } else {
+        Vec::new()
+    };
+
+    let title = match (params.title, zettels.as_slice()) {
+        (Some(title), _) => title,
+        (None, [(_, record)]) => record.title.clone(),
+        (None, _) => "Commonplace".to_string(),
+    };
+
+    Ok(([(header::CONTENT_TYPE, "text/html; charset=utf-8")], document(&title, &zettels, &transcluded)))
+}
+
+/// Render a print-ready document containing `zettels`, followed by `transcluded`. Links between Zettels in the
+/// document point within it, and links to any other Zettels are rendered as plain text.
+pub fn document(
+    title: &str,
+    zettels: &[(ZettelId, ZettelRecord)],
+    transcluded: &[(ZettelId, ZettelRecord)],
+) -> String {
+    let titles = zettels
+        .iter()
+        .chain(transcluded)
+        .map(|(id, record)| {
+            let title = if record.title.is_empty() { id.0.to_string() } else { record.title.clone() };
+            (*id, title)
+        })
+        .collect::<BTreeMap<_, _>>();
+    let link = |target: ZettelId| match titles.get(&target) {
+        Some(title) => {
+            format!("<a class=\"zettel-link\" href=\"#{}\">{}</a>", anchor(target), html::escape(title))
+        }
+        None => "<span class=\"zettel-link\">another Zettel</span>".to_string(),
+    };
+
+    let mut toc = String::from("<nav class=\"toc\">\n<h2>Contents</h2>\n<ol>\n");
+    let mut body = String::new();
+    let sections = [(None, zettels), (Some("Linked Zettels"), transcluded)];
+    for (heading, zettels) in sections {
+        if zettels.is_empty() {
+            continue;
+        }
+        if let Some(heading) = heading {
+            toc.push_str(&format!("</ol>\n<h3>{}</h3>\n<ol>\n", heading));
+            body.push_str(&format!("<h1 class=\"part\">{}</h1>\n", heading));
+        }
+
+        for (id, record) in zettels {
+            let (rendered, headings) =
+                html::render_with_headings(&record.content, &link, &format!("{}-heading", anchor(*id)));
+
+            toc.push_str(&format!("<li><a href=\"#{}\">{}</a>", anchor(*id), html::escape(&titles[id])));
+            toc_headings(&mut toc, &headings);
+            toc.push_str("</li>\n");
+
+            body.push_str(&format!(
+                "<section class=\"zettel\" id=\"{}\">\n<h1>{}</h1>\n{}</section>\n",
+                anchor(*id),
+                html::escape(&titles[id]),
+                rendered
+            ));
+        }
+    }
+    toc.push_str("</ol>\n</nav>\n");
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n\
+         <body>\n<header>\n<h1 class=\"title\">{}</h1>\n</header>\n{}{}</body>\n</html>\n",
+        html::escape(title),
+        PRINT_STYLE,
+        html::escape(title),
+        toc,
+        body
+    )
+}
+
+/// Add a Zettel's headings to the table of contents, nested by level. Headings can skip levels, so each one is
+/// nested beneath the closest heading before it with a lower level.
+fn toc_headings(toc: &mut String, headings: &[Heading]) {
+    let mut open: Vec<usize> = Vec::new();
+    for heading in headings {
+        while open.last().is_some_and(|level| *level > heading.level) {
+            toc.push_str("</li>\n</ol>\n");
+            open.pop();
+        }
+
+        let entry = format!("<li><a href=\"#{}\">{}</a>", html::escape(&heading.id), html::escape(&heading.text));
+        if open.last() == Some(&heading.level) {
+            toc.push_str("</li>\n");
+        } else {
+            toc.push_str("\n<ol>\n");
+            open.push(heading.level);
+        }
+        toc.push_str(&entry);
+    }
+    for _ in open {
+        toc.push_str("</li>\n</ol>\n");
+    }
+}
+
+fn anchor(id: ZettelId) -> String {
+    format!("zettel-{}", id.0)
+}
+
+const PRINT_STYLE: &str = "body { font-family: Georgia, serif; line-height: 1.5; max-width: 42em; margin: 2em auto; \
+                           padding: 0 1em; color: #000; } a { color: inherit; } pre { white-space: pre-wrap; \
+                           background: #f4f4f4; padding: 0.5em; } blockquote { border-left: 3px solid #999; \
+                           margin-left: 0; padding-left: 1em; } table { border-collapse: collapse; } th, td { border: \
+                           1px solid #999; padding: 0.25em 0.5em; } img { max-width: 100%; } ul[data-type=taskList] { \
+                           list-style: none; padding-left: 0.5em; } li[data-type=taskItem] { display: flex; gap: \
+                           0.5em; } .toc ol { list-style: none; padding-left: 1.5em; } .title { font-size: 2.5em; } \
+                           @page { margin: 2cm; } @media print { body { max-width: none; margin: 0; padding: 0; } \
+                           section.zettel, h1.part { break-before: page; } pre, table, img, blockquote { \
+                           break-inside: avoid; } h1, h2, h3, h4, h5, h6 { break-after: avoid; } }";
@@ -0,0 +1,127 @@
+//! Publishing a set of Zettels as a static website. Each Zettel becomes its own page, with links between the
+//! published Zettels and a list of backlinks, and an index page lists them all and can search them using a JSON
+//! search index that's generated alongside.
+
+use crate::{html, store::ZettelRecord};
+use commonplace::ZettelId;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io,
+    path::Path,
+};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SearchEntry {
+    pub id: ZettelId,
+    pub title: String,
+    /// The path of the Zettel's page, relative to the site's root.
+    pub path: String,
+    pub tags: BTreeSet<String>,
+    /// The plain text of the Zettel, for matching against.
+    pub text: String,
+}
+
+/// Write a static site containing the given Zettels to `dir`. Links to Zettels that aren't being published are
+/// rendered as plain text, so the site doesn't have any broken links.
+pub fn publish(zettels: &[(ZettelId, ZettelRecord)], dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let titles = zettels.iter().map(|(id, record)| (*id, display_title(*id, record))).collect::<BTreeMap<_, _>>();
+    let mut backlinks = BTreeMap::<ZettelId, BTreeSet<ZettelId>>::new();
+    for (id, record) in zettels {
+        for target in record.content.links() {
+            if titles.contains_key(&target) && target != *id {
+                backlinks.entry(target).or_default().insert(*id);
+            }
+        }
+    }
+
+    let link = |target: ZettelId| match titles.get(&target) {
+        Some(title) => {
+            format!("<a class=\"zettel-link\" href=\"{}\">{}</a>", page_path(target), html::escape(title))
+        }
+        None => "<span class=\"zettel-link\">an unpublished Zettel</span>".to_string(),
+    };
+
+    let mut search_index = Vec::new();
+    for (id, record) in zettels {
+        let mut body = html::render(&record.content, &link);
+
+        if let Some(backlinks) = backlinks.get(id) {
+            body.push_str("<section class=\"backlinks\">\n<h2>Backlinks</h2>\n<ul>\n");
+            for backlink in backlinks {
+                body.push_str(&format!("<li>{}</li>\n", link(*backlink)));
+            }
+            body.push_str("</ul>\n</section>\n");
+        }
+        body.push_str("<nav><a href=\"index.html\">All pages</a></nav>\n");
+
+        fs::write(dir.join(page_path(*id)), html::page(&titles[id], &body))?;
+        search_index.push(SearchEntry {
+            id: *id,
+            title: titles[id].clone(),
+            path: page_path(*id),
+            tags: record.tags.clone(),
+            text: record.content.index(),
+        });
+    }
+
+    let mut pages = search_index.iter().collect::<Vec<_>>();
+    pages.sort_by_key(|entry| entry.title.to_lowercase());
+    let mut body = String::from(
+        "<input id=\"search\" type=\"search\" placeholder=\"Search\" autocomplete=\"off\">\n<ul id=\"pages\">\n",
+    );
+    for entry in pages {
+        body.push_str(&format!(
+            "<li data-id=\"{}\"><a href=\"{}\">{}</a></li>\n",
+            entry.id.0,
+            entry.path,
+            html::escape(&entry.title)
+        ));
+    }
+    body.push_str("</ul>\n");
+    body.push_str(SEARCH_SCRIPT);
+
+    fs::write(dir.join("index.html"), html::page("Index", &body))?;
+    fs::write(dir.join("search.json"), serde_json::to_vec(&search_index)?)?;
+
+    Ok(())
+}
+
+fn page_path(id: ZettelId) -> String {
+    format!("{}.html", id.0)
+}
+
+fn display_title(id: ZettelId, record: &ZettelRecord) -> String {
+    if record.title.is_empty() {
+        id.0.to_string()
+    } else {
+        record.title.clone()
+    }
+}
+
+/*
+ * Filters the index page's list of pages to those containing every word typed into the search box. The search
+ * index is only fetched the first time something is searched for.
+ */
+const SEARCH_SCRIPT: &str = r##"<script>
+let searchIndex = null;
+document.getElementById("search").addEventListener("input", async (event) => {
+  if (searchIndex === null) {
+    searchIndex = await (await fetch("search.json")).json();
+  }
+  const words = event.target.value.toLowerCase().split(/\s+/).filter((word) => word.length > 0);
+  const matching = new Set(searchIndex
+    .filter((entry) => {
+      const text = [entry.title, entry.text, ...entry.tags].join(" ").toLowerCase();
+      return words.every((word) => text.includes(word));
+    })
+    .map((entry) => String(entry.id)));
+  for (const item of document.querySelectorAll("#pages li")) {
+    item.hidden = !matching.has(item.dataset.id);
+  }
+});
+</script>
+"##;
@@ -0,0 +1,105 @@
+//! Public, read-only links to individual Zettels. Sharing a Zettel gives it an unguessable token, and anyone with
+//! the token can view the Zettel as a standalone page under `/shared/:token`, without access to the editor or the
+//! API.
+
+use crate::{
+    audit::Action,
+    html,
+    store::{Visibility, ZettelRecord},
+    workspace::Access,
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Html,
+    Extension,
+    Json,
+};
+use commonplace::ZettelId;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Shared {
+    pub token: String,
+    /// The path the Zettel can be viewed at.
+    pub url: String,
+}
+
+pub async fn share(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(id): Path<ZettelId>,
+) -> Result<Json<Shared>, StatusCode> {
+    access.write(state.store.get(id))?;
+    let token = state
+        .blocking(move |state| {
+            let token = state.store.share(id)?;
+            state.audit.record(&access.username, Action::ShareZettel, Some(id), None);
+            Some(token)
+        })
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(Shared { url: shared_url(&token), token }))
+}
+
+pub async fn unshare(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(id): Path<ZettelId>,
+) -> Result<(), StatusCode> {
+    access.write(state.store.get(id))?;
+    let unshared = state
+        .blocking(move |state| {
+            let unshared = state.store.unshare(id);
+            if unshared {
+                state.audit.record(&access.username, Action::UnshareZettel, Some(id), None);
+            }
+            unshared
+        })
+        .await;
+    if unshared {
+        Ok(())
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+pub async fn shared(
+    State(state): State<Arc<AppState>>,
+    Path(token): Path<String>,
+) -> Result<Html<String>, StatusCode> {
+    let (_, record) = state.store.shared(&token).ok_or(StatusCode::NOT_FOUND)?;
+
+    /*
+     * Links to other shared Zettels can be followed, but we don't want to leak anything about Zettels that haven't
+     * been shared (even their IDs), so links to them are rendered as just their titles. Even the titles are only
+     * shown if whoever shared this Zettel could see them, so sharing can't be used to reveal private Zettels.
+     */
+    let owner = record
+        .permissions
+        .owner
+        .as_ref()
+        .map(|owner| Access { username: owner.clone(), workspaces: state.workspaces.memberships(owner) });
+    let visible = |target: &ZettelRecord| match &owner {
+        Some(owner) => owner.can_read(target),
+        None => target.permissions.owner.is_none() || target.permissions.visibility == Visibility::Public,
+    };
+    let link = |target: ZettelId| match state.store.get(target).filter(visible) {
+        Some(ZettelRecord { share_token: Some(token), title, .. }) => {
+            let text = if title.is_empty() { target.0.to_string() } else { title };
+            format!("<a class=\"zettel-link\" href=\"{}\">{}</a>", shared_url(&token), html::escape(&text))
+        }
+        Some(ZettelRecord { title, .. }) if !title.is_empty() => {
+            format!("<span class=\"zettel-link\">{}</span>", html::escape(&title))
+        }
+        _ => "<span class=\"zettel-link\">an unshared Zettel</span>".to_string(),
+    };
+
+    Ok(Html(html::page(&record.title, &html::render(&record.content, &link))))
+}
+
+fn shared_url(token: &str) -> String {
+    format!("/shared/{}", token)
+}
@@ -0,0 +1,222 @@
+//! Regular automatic snapshots of the database, so that if the `db/` directory is corrupted, it can be recovered
+//! by restoring the latest one. Snapshots are normal backups (see `backup`), written to a directory, and the oldest
+//! are deleted so only a fixed number are kept.
+//!
+//! Where snapshots are written is set with the rest of the server's config (see `config`), and the rest is
+//! configured through environment variables for now:
+//!    - `COMMONPLACE_SNAPSHOT_INTERVAL` - how often to take a snapshot, in seconds (default an hour). Setting this
+//!      to `0` turns snapshots off.
+//!    - `COMMONPLACE_SNAPSHOT_KEEP` - how many snapshots to keep (default 24)
+
+use crate::{auth::CurrentUser, backup, store::ZettelStore, AppState};
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{error, info, warn};
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_EXTENSION: &str = ".ndjson";
+
+#[derive(Clone, Debug)]
+pub struct SnapshotConfig {
+    pub dir: PathBuf,
+    /// How often to take a snapshot. If this is `None`, snapshots are turned off.
+    pub interval: Option<Duration>,
+    pub keep: usize,
+}
+
+impl SnapshotConfig {
+    pub fn from_env(dir: PathBuf) -> SnapshotConfig {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let interval = var("COMMONPLACE_SNAPSHOT_INTERVAL")
+            .map(|interval| interval.parse::<u64>().expect("COMMONPLACE_SNAPSHOT_INTERVAL should be a number"))
+            .unwrap_or(60 * 60);
+        let keep = var("COMMONPLACE_SNAPSHOT_KEEP")
+            .map(|keep| keep.parse::<usize>().expect("COMMONPLACE_SNAPSHOT_KEEP should be a number"))
+            .unwrap_or(24);
+
+        SnapshotConfig {
+            dir,
+            interval: if interval == 0 { None } else { Some(Duration::from_secs(interval)) },
+            keep: keep.max(1),
+        }
+    }
+}
+
+pub struct Snapshots {
+    pub config: SnapshotConfig,
+    status: Mutex<SnapshotStatus>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotStatus {
+    pub dir: PathBuf,
+    /// How often snapshots are taken, in seconds. If this is `None`, snapshots are turned off.
+    pub interval: Option<u64>,
+    pub keep: usize,
+    pub last_success: Option<SnapshotInfo>,
+    /// If the last attempt to take a snapshot failed, when it happened and why.
+    pub last_failure: Option<SnapshotFailure>,
+    pub next_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotInfo {
+    pub path: PathBuf,
+    pub taken_at: DateTime<Utc>,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotFailure {
+    pub at: DateTime<Utc>,
+    pub error: String,
+}
+
+impl Snapshots {
+    pub fn new(config: SnapshotConfig) -> Arc<Snapshots> {
+        let status = SnapshotStatus {
+            dir: config.dir.clone(),
+            interval: config.interval.map(|interval| interval.as_secs()),
+            keep: config.keep,
+            last_success: None,
+            last_failure: None,
+            next_at: None,
+        };
+        Arc::new(Snapshots { config, status: Mutex::new(status) })
+    }
+}
+
+pub async fn take_snapshots(state: Arc<AppState>) {
+    let Some(period) = state.snapshots.config.interval else {
+        return;
+    };
+
+    /*
+     * The first tick of an interval completes immediately, which would take a snapshot every time the server
+     * starts. Skip it, so the first snapshot is taken one interval after starting.
+     */
+    let mut interval = tokio::time::interval(period);
+    interval.tick().await;
+    loop {
+        state.snapshots.status.lock().unwrap().next_at = Some(Utc::now() + period);
+        interval.tick().await;
+
+        let snapshot_state = state.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            take_snapshot(&snapshot_state.store, &snapshot_state.snapshots.config)
+        })
+        .await
+        .unwrap();
+
+        let mut status = state.snapshots.status.lock().unwrap();
+        match result {
+            Ok(snapshot) => {
+                info!("Took snapshot {}", snapshot.path.display());
+                status.last_success = Some(snapshot);
+                status.last_failure = None;
+            }
+            Err(err) => {
+                error!("Failed to take snapshot: {:?}", err);
+                status.last_failure = Some(SnapshotFailure { at: Utc::now(), error: err.to_string() });
+            }
+        }
+    }
+}
+
+/// Take a snapshot of the store, and then delete the oldest snapshots if there are more than we should keep.
+pub fn take_snapshot(store: &ZettelStore, config: &SnapshotConfig) -> io::Result<SnapshotInfo> {
+    fs::create_dir_all(&config.dir)?;
+
+    /*
+     * Write the snapshot to a temporary file, and only give it its real name once it's complete, so we never
+     * mistake a partially-written snapshot for a good one.
+     */
+    let taken_at = Utc::now();
+    let name = format!("{}{}{}", SNAPSHOT_PREFIX, taken_at.format("%Y%m%dT%H%M%SZ"), SNAPSHOT_EXTENSION);
+    let path = config.dir.join(&name);
+    let partial = config.dir.join(format!(".{}.partial", name));
+
+    let counts = backup::backup(store, BufWriter::new(File::create(&partial)?))?;
+    File::open(&partial)?.sync_all()?;
+    fs::rename(&partial, &path)?;
+
+    rotate(&config.dir, config.keep)?;
+    Ok(SnapshotInfo { bytes: fs::metadata(&path)?.len(), path, taken_at, entries: counts.values().sum() })
+}
+
+/// Warn about any snapshots that were taken before encryption was turned on, as the Zettels in them aren't
+/// encrypted. They're left alone, as they may be the only copy of something, but should be deleted once they're no
+/// longer needed (rotating them out would also do it eventually).
+pub fn warn_if_unencrypted(store: &ZettelStore, config: &SnapshotConfig) {
+    if !store.is_encrypted() {
+        return;
+    }
+
+    let unencrypted = snapshots(&config.dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| {
+            let Ok(file) = File::open(config.dir.join(name)) else {
+                return false;
+            };
+            let mut header = String::new();
+            BufReader::new(file).read_line(&mut header).is_ok()
+                && serde_json::from_str::<backup::Header>(&header).is_ok_and(|header| header.encryption.is_none())
+        })
+        .collect::<Vec<_>>();
+    if !unencrypted.is_empty() {
+        warn!(
+            "Zettels are encrypted, but {} snapshots in {} were taken before they were, and aren't encrypted: {}",
+            unencrypted.len(),
+            config.dir.display(),
+            unencrypted.join(", ")
+        );
+    }
+}
+
+/// The names of the snapshots in `dir`, oldest first.
+fn snapshots(dir: &Path) -> io::Result<Vec<String>> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(SNAPSHOT_EXTENSION) {
+            snapshots.push(name);
+        }
+    }
+
+    // Snapshots are named by when they were taken, so sorting them by name puts the oldest first
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+fn rotate(dir: &Path, keep: usize) -> io::Result<()> {
+    let snapshots = snapshots(dir)?;
+    let excess = snapshots.len().saturating_sub(keep);
+    for name in &snapshots[..excess] {
+        info!("Removing old snapshot {}", name);
+        fs::remove_file(dir.join(name))?;
+    }
+
+    Ok(())
+}
+
+/// The status of snapshots. This includes where they're written and why the last one failed, so only admins can
+/// see it.
+pub async fn status(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+) -> Result<Json<SnapshotStatus>, StatusCode> {
+    if !user.is_admin() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(Json(state.snapshots.status.lock().unwrap().clone()))
+}
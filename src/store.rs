@@ -1,44 +1,278 @@
-use crate::zettel::{ZettelContent, ZettelUpdate};
+use crate::{
+    encryption::{Cipher, EncryptionConfig, KeyParams, KEY_PARAMS_KEY, META_TREE},
+    tags,
+    workspace::{user_key, workspace_key, Access, EVERYONE},
+    zettel::{ZettelContent, ZettelUpdate},
+};
+use chrono::{DateTime, Duration, Utc};
 use commonplace::ZettelId;
+use rand::{rngs::OsRng, RngCore};
 use serde::{Deserialize, Serialize};
-use std::{convert::TryInto, ops::Deref};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryInto,
+    ops::Deref,
+    path::Path,
+    sync::{Arc, RwLock, RwLockWriteGuard},
+};
+use tracing::info;
+
+/// The name of the tree that Zettels are stored in. This includes the format version, so that Zettels in different
+/// formats are never mixed up.
+pub const ZETTELS_TREE: &str = "zettels_v2";
+
+/// Set in the meta tree once the tag index has been filled from every Zettel's tags. Databases from before there
+/// were tags only get entries in it when a Zettel is written, so it's filled once when this is missing.
+const TAG_INDEX_KEY: &[u8] = b"tag_index";
+
+/// Set in the meta tree once every Zettel has been encrypted. After that, an unencrypted Zettel can only have been
+/// put there by someone going around Commonplace, so it's refused rather than trusted.
+const ALL_ENCRYPTED_KEY: &[u8] = b"all_encrypted";
 
 pub struct ZettelStore {
-    tree: sled::Tree,
+    db: sled::Db,
+    zettels: sled::Tree,
+    /// Index from tags to the Zettels that have them. Keys are the tag, followed by a NUL byte, followed by the
+    /// encoded ID of the Zettel. Values are empty.
+    tags: sled::Tree,
+    /// Index from share tokens to the Zettel they share. Keys are the token, and values are the encoded ID.
+    shares: sled::Tree,
+    /*
+     * Changes to Zettels can touch several trees, so to take a consistent snapshot of the whole database we need to
+     * stop changes while it's taken. Anything that changes the store holds this for reading, so they can still run
+     * concurrently, and snapshots hold it for writing. Waiting for it blocks the thread, so request handlers make
+     * changes through `AppState::blocking`.
+     */
+    snapshot_lock: Arc<RwLock<()>>,
+    /// Database-wide settings, like the encryption parameters.
+    meta: sled::Tree,
+    encryption: Option<EncryptionConfig>,
+    /// The key Zettels are encrypted with, if encryption is turned on.
+    cipher: Option<Cipher>,
+    key_params: Option<KeyParams>,
+    /// Whether every Zettel is known to be encrypted (see `ALL_ENCRYPTED_KEY`).
+    all_encrypted: bool,
+    /// Set if the tag index was filled when the store was opened, so Zettels' tags changed and need reindexing.
+    filled_tag_index: bool,
 }
 
 impl ZettelStore {
-    pub fn new() -> ZettelStore {
-        ZettelStore { tree: sled::open("db").unwrap().open_tree("zettels_v2").unwrap() }
+    pub fn new(path: &Path) -> ZettelStore {
+        let db = sled::open(path).unwrap();
+        let mut store = ZettelStore {
+            zettels: db.open_tree(ZETTELS_TREE).unwrap(),
+            tags: db.open_tree("tags").unwrap(),
+            shares: db.open_tree("shares").unwrap(),
+            snapshot_lock: Arc::new(RwLock::new(())),
+            meta: db.open_tree(META_TREE).unwrap(),
+            encryption: EncryptionConfig::from_env(),
+            cipher: None,
+            key_params: None,
+            all_encrypted: false,
+            filled_tag_index: false,
+            db,
+        };
+        store.set_up_encryption();
+        store.fill_tag_index();
+        store
+    }
+
+    /// Fill the tag index from the Zettels, if it's never been done for this database. An empty database is left
+    /// alone, as a backup might be about to be restored into it.
+    fn fill_tag_index(&mut self) {
+        if self.meta.get(TAG_INDEX_KEY).unwrap().is_some() || self.zettels.is_empty() {
+            return;
+        }
+
+        match self.migrate() {
+            Ok(count) => info!("Filled the tag index from {} existing Zettels", count),
+            Err((id, err)) => {
+                panic!("Zettel {} can't be read ({:?}), so the tag index can't be filled", id.0, err)
+            }
+        }
+        self.filled_tag_index = true;
+    }
+
+    /// Whether the tag index was filled when the store was opened. If it was, the search index needs rebuilding too,
+    /// as the Zettels' tags will have changed.
+    pub fn filled_tag_index(&self) -> bool {
+        self.filled_tag_index
+    }
+
+    /// Get the key that Zettels are encrypted with, if encryption is turned on. The first time it's turned on, a new
+    /// key is generated, and any Zettels that were already in the database are encrypted with it. Once they all are,
+    /// that's recorded, and from then on unencrypted Zettels are refused.
+    fn set_up_encryption(&mut self) {
+        let stored = self.meta.get(KEY_PARAMS_KEY).unwrap().map(|bytes| serde_cbor::from_slice(&bytes).unwrap());
+        let Some(config) = &self.encryption else {
+            if stored.is_some() {
+                panic!(
+                    "The database is encrypted, so COMMONPLACE_ENCRYPTION_PASSPHRASE or \
+                     COMMONPLACE_ENCRYPTION_KEY_FILE needs to be set"
+                );
+            }
+            return;
+        };
+
+        let (cipher, params) = match stored {
+            Some(params) => {
+                let cipher = Cipher::derive(config, &params).expect("Wrong encryption passphrase or key file");
+                (cipher, params)
+            }
+            None => {
+                let (cipher, params) = Cipher::generate(config);
+                self.meta.insert(KEY_PARAMS_KEY, serde_cbor::to_vec(&params).unwrap()).unwrap();
+                (cipher, params)
+            }
+        };
+        self.cipher = Some(cipher);
+        self.key_params = Some(params);
+        if self.meta.get(ALL_ENCRYPTED_KEY).unwrap().is_some() {
+            self.all_encrypted = true;
+            return;
+        }
+
+        let mut encrypted = 0;
+        for entry in self.zettels.iter() {
+            let (key, value) = entry.unwrap();
+            if !is_encrypted(&value) {
+                let id = ZettelId::decode(key.deref().try_into().unwrap());
+                let record = self.decode(id, &value).unwrap();
+                self.zettels.compare_and_swap(key, Some(value), Some(self.encode(id, &record))).unwrap().unwrap();
+                encrypted += 1;
+            }
+        }
+        if encrypted > 0 {
+            info!("Encrypted {} existing Zettels", encrypted);
+        }
+        self.meta.insert(ALL_ENCRYPTED_KEY, &[]).unwrap();
+        self.all_encrypted = true;
+        self.flush();
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// The parameters of the key that Zettels are encrypted with, if encryption is turned on. These are needed,
+    /// along with the passphrase or key file, to decrypt them.
+    pub fn key_params(&self) -> Option<&KeyParams> {
+        self.key_params.as_ref()
+    }
+
+    /// Get the key described by `params`, derived from the passphrase or key file this store was opened with. This
+    /// is used to read Zettels from encrypted backups, which may have been made with a different salt.
+    pub fn cipher_for(&self, params: &KeyParams) -> Option<Cipher> {
+        Cipher::derive(self.encryption.as_ref()?, params)
+    }
+
+    pub fn encode(&self, id: ZettelId, record: &ZettelRecord) -> Vec<u8> {
+        record.serialize(id, self.cipher.as_ref())
+    }
+
+    /// Decode a Zettel as stored in this database. Unlike `ZettelRecord::deserialize`, this refuses unencrypted
+    /// Zettels once they should all be encrypted.
+    pub fn decode(&self, id: ZettelId, bytes: &[u8]) -> Result<ZettelRecord, DeserializeError> {
+        let record = ZettelRecord::deserialize(id, bytes, self.cipher.as_ref())?;
+        if self.all_encrypted && !is_encrypted(bytes) {
+            return Err(DeserializeError::Unencrypted);
+        }
+        Ok(record)
+    }
+
+    /// A handle to the whole database, for things like generating unique IDs.
+    pub fn db(&self) -> sled::Db {
+        self.db.clone()
+    }
+
+    /// Get every tree in the database, by name. This includes trees that this version of Commonplace doesn't know
+    /// about, so that tools like backups can deal with the database as a whole.
+    pub fn trees(&self) -> Vec<(String, sled::Tree)> {
+        self.db
+            .tree_names()
+            .into_iter()
+            .map(|name| {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                let tree = self.tree(&name);
+                (name, tree)
+            })
+            .collect()
+    }
+
+    /// Open the tree with the given name, creating it if it doesn't exist.
+    pub fn tree(&self, name: &str) -> sled::Tree {
+        self.db.open_tree(name).unwrap()
+    }
+
+    /// Stop any changes being made to the store until the returned guard is dropped, so that a consistent snapshot
+    /// of it can be taken.
+    pub fn pause_writes(&self) -> RwLockWriteGuard<'_, ()> {
+        self.snapshot_lock.write().unwrap()
+    }
+
+    /// The lock that `pause_writes` takes, for anything else that writes to the database (like the audit log) to
+    /// hold for reading while it does.
+    pub fn snapshot_lock(&self) -> Arc<RwLock<()>> {
+        self.snapshot_lock.clone()
+    }
+
+    /// Make sure everything written to the database has been persisted to disk.
+    pub fn flush(&self) {
+        self.db.flush().unwrap();
     }
 
     /// Try to create a new Zettel with a generated ID. Returns `None` if a duplicate ID is generated - this means
     /// client(s) are trying to create Zettels too fast (more than one a second).
-    pub fn create(&self) -> Option<ZettelId> {
+    pub fn create(&self, permissions: Permissions) -> Option<ZettelId> {
+        let _writing = self.snapshot_lock.read().unwrap();
         let id = ZettelId::generate();
+        let record = ZettelRecord { permissions, ..ZettelRecord::new() };
 
         /*
          * We're using the compare-and-swap to detect duplicate ID generation - if there's already an entry for
          * that ID, turn the error into `None`.
          */
-        self.tree
-            .compare_and_swap(&id.encode(), None::<&[u8]>, Some(ZettelRecord::new().serialize()))
-            .unwrap()
-            .ok()?;
+        self.zettels.compare_and_swap(id.encode(), None::<&[u8]>, Some(self.encode(id, &record))).unwrap().ok()?;
         Some(id)
     }
 
+    /// Create a new Zettel with an ID based on the given time, rather than the current time. This is used when
+    /// importing Zettels from elsewhere, to preserve when they were originally created. If that ID is already taken,
+    /// the time is moved on a second at a time until a free ID is found.
+    pub fn create_at(&self, mut datetime: DateTime<Utc>, permissions: Permissions) -> ZettelId {
+        let _writing = self.snapshot_lock.read().unwrap();
+        let record = ZettelRecord { permissions, ..ZettelRecord::new() };
+        loop {
+            let id = ZettelId::from_datetime(datetime);
+            let created = self
+                .zettels
+                .compare_and_swap(id.encode(), None::<&[u8]>, Some(self.encode(id, &record)))
+                .unwrap()
+                .is_ok();
+            if created {
+                return id;
+            }
+
+            datetime += Duration::seconds(1);
+        }
+    }
+
     pub fn get(&self, id: ZettelId) -> Option<ZettelRecord> {
-        self.tree.get(id.encode()).unwrap().map(|bytes| ZettelRecord::deserialize(&bytes).unwrap())
+        self.zettels.get(id.encode()).unwrap().map(|bytes| self.decode(id, &bytes).unwrap())
     }
 
+    /// Get every Zettel. This doesn't check who can see them, so requests from users should use `visible` instead.
     pub fn all(&self) -> Vec<(ZettelId, ZettelRecord)> {
-        self.tree
+        self.zettels
             .iter()
             .filter_map(|entry| {
                 if let Ok((key, value)) = entry {
                     let id = ZettelId::decode(key.deref().try_into().unwrap());
-                    let zettel = ZettelRecord::deserialize(&value).unwrap();
+                    let zettel = self.decode(id, &value).unwrap();
                     Some((id, zettel))
                 } else {
                     println!("Weird entry when iterating Zettels: {:?}", entry);
@@ -48,18 +282,295 @@ impl ZettelStore {
             .collect()
     }
 
+    /// Get every Zettel that can be seen with the given access.
+    pub fn visible(&self, access: &Access) -> Vec<(ZettelId, ZettelRecord)> {
+        self.all().into_iter().filter(|(_, record)| access.can_read(record)).collect()
+    }
+
+    /// Change who owns a Zettel, and who can see it.
+    pub fn set_permissions(&self, id: ZettelId, permissions: Permissions) {
+        let _writing = self.snapshot_lock.read().unwrap();
+        self.zettels
+            .fetch_and_update(id.encode(), |bytes| {
+                let mut zettel = self.decode(id, bytes?).unwrap();
+                zettel.permissions = permissions.clone();
+                Some(self.encode(id, &zettel))
+            })
+            .unwrap();
+    }
+
     pub fn update(&self, id: ZettelId, update: ZettelUpdate) {
-        self.tree
-            .update_and_fetch(&id.encode(), |old| {
-                let mut zettel = ZettelRecord::deserialize(old.unwrap()).unwrap();
+        let _writing = self.snapshot_lock.read().unwrap();
+        (&self.zettels, &self.tags)
+            .transaction(|(zettels, tags)| {
+                let mut zettel = self.decode(id, &zettels.get(id.encode())?.unwrap()).unwrap();
+                let old_tags = zettel.tags.clone();
 
                 zettel.title = update.title.clone();
                 zettel.content = update.content.clone();
+                if let Some(explicit_tags) = &update.tags {
+                    zettel.explicit_tags = explicit_tags.clone();
+                }
+                zettel.tags = &zettel.explicit_tags | &zettel.content.tags();
 
-                Some(zettel.serialize())
+                update_tag_index(tags, id, &old_tags, &zettel.tags)?;
+                zettels.insert(&id.encode(), self.encode(id, &zettel))?;
+                Ok::<_, ConflictableTransactionError>(())
             })
             .unwrap();
     }
+
+    /// Get the IDs of all the Zettels with the given tag.
+    pub fn tagged(&self, tag: &str) -> Vec<ZettelId> {
+        let mut prefix = Vec::from(tag.as_bytes());
+        prefix.push(0);
+
+        self.tags.scan_prefix(prefix).keys().map(|key| split_tag_key(&key.unwrap()).1).collect()
+    }
+
+    /// Get all the Zettels with the given tag, or any tag nested beneath it, along with the tag they have. A
+    /// Zettel will appear multiple times if it has more than one of the tags.
+    pub fn tagged_within(&self, tag: &str) -> Vec<(String, ZettelId)> {
+        /*
+         * Nested tags all start with the parent tag, so we can find them with a prefix scan and then filter out
+         * tags like `foobar` when looking for `foo`.
+         */
+        self.tags
+            .scan_prefix(tag.as_bytes())
+            .keys()
+            .filter_map(|key| {
+                let key = key.unwrap();
+                let (found, id) = split_tag_key(&key);
+                if tags::is_within(found, tag) {
+                    Some((found.to_string(), id))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Rename the tag `from` (and any tags nested beneath it) to `to`, rewriting every affected Zettel's explicit
+    /// tags and `#hashtags`. This is done in a single transaction, so either every Zettel is updated or none are.
+    /// Only Zettels that `include` returns `true` for are changed. Returns the updated Zettels, so they can be
+    /// reindexed.
+    pub fn rename_tag(
+        &self,
+        from: &str,
+        to: &str,
+        include: &dyn Fn(&ZettelRecord) -> bool,
+    ) -> Vec<(ZettelId, ZettelRecord)> {
+        let _writing = self.snapshot_lock.read().unwrap();
+        let mut affected = self.tagged_within(from).into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+        affected.sort_by_key(|id| id.0);
+        affected.dedup();
+
+        (&self.zettels, &self.tags)
+            .transaction(|(zettels, tag_index)| {
+                let mut updated = Vec::new();
+
+                for &id in &affected {
+                    let mut zettel = match zettels.get(id.encode())? {
+                        Some(bytes) => self.decode(id, &bytes).unwrap(),
+                        None => continue,
+                    };
+                    if !include(&zettel) {
+                        continue;
+                    }
+                    let old_tags = zettel.tags.clone();
+
+                    zettel.explicit_tags = zettel
+                        .explicit_tags
+                        .iter()
+                        .map(|tag| tags::rename_tag(tag, from, to).unwrap_or_else(|| tag.clone()))
+                        .collect();
+                    zettel.content.rename_tag(from, to);
+                    zettel.tags = &zettel.explicit_tags | &zettel.content.tags();
+
+                    update_tag_index(tag_index, id, &old_tags, &zettel.tags)?;
+                    zettels.insert(&id.encode(), self.encode(id, &zettel))?;
+                    updated.push((id, zettel));
+                }
+
+                Ok::<_, ConflictableTransactionError>(updated)
+            })
+            .unwrap()
+    }
+
+    /// Share a Zettel publicly, returning the token that can be used to view it. If the Zettel is already shared,
+    /// its existing token is returned. Returns `None` if the Zettel doesn't exist.
+    pub fn share(&self, id: ZettelId) -> Option<String> {
+        let _writing = self.snapshot_lock.read().unwrap();
+        let token = random_token();
+        (&self.zettels, &self.shares)
+            .transaction(|(zettels, shares)| {
+                let mut zettel = match zettels.get(id.encode())? {
+                    Some(bytes) => self.decode(id, &bytes).unwrap(),
+                    None => return Ok(None),
+                };
+                if let Some(existing) = &zettel.share_token {
+                    return Ok(Some(existing.clone()));
+                }
+
+                zettel.share_token = Some(token.clone());
+                shares.insert(token.as_bytes(), &id.encode())?;
+                zettels.insert(&id.encode(), self.encode(id, &zettel))?;
+                Ok::<_, ConflictableTransactionError>(Some(token.clone()))
+            })
+            .unwrap()
+    }
+
+    /// Stop sharing a Zettel. Its old token stops working, and sharing it again will produce a new one. Returns
+    /// `false` if the Zettel doesn't exist.
+    pub fn unshare(&self, id: ZettelId) -> bool {
+        let _writing = self.snapshot_lock.read().unwrap();
+        (&self.zettels, &self.shares)
+            .transaction(|(zettels, shares)| {
+                let mut zettel = match zettels.get(id.encode())? {
+                    Some(bytes) => self.decode(id, &bytes).unwrap(),
+                    None => return Ok(false),
+                };
+
+                if let Some(token) = zettel.share_token.take() {
+                    shares.remove(token.as_bytes())?;
+                    zettels.insert(&id.encode(), self.encode(id, &zettel))?;
+                }
+                Ok::<_, ConflictableTransactionError>(true)
+            })
+            .unwrap()
+    }
+
+    /// Get the Zettel shared with the given token, if there is one.
+    pub fn shared(&self, token: &str) -> Option<(ZettelId, ZettelRecord)> {
+        let id = ZettelId::decode(self.shares.get(token.as_bytes()).unwrap()?.deref().try_into().unwrap());
+        Some((id, self.get(id)?))
+    }
+
+    /// Look for problems with the Zettels: ones that can't be read, and entries in the tag and share indexes that
+    /// don't match them. Returns a description of each problem found.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut zettels = BTreeMap::new();
+        for entry in self.zettels.iter() {
+            let (key, value) = entry.unwrap();
+            let Ok(key) = key.deref().try_into() else {
+                problems.push(format!("Zettel with invalid key {:?}", key));
+                continue;
+            };
+            let id = ZettelId::decode(key);
+            match self.decode(id, &value) {
+                Ok(zettel) => {
+                    zettels.insert(id, zettel);
+                }
+                Err(err) => problems.push(format!("Zettel {} can't be read: {:?}", id.0, err)),
+            }
+        }
+
+        let mut indexed_tags = BTreeSet::new();
+        for key in self.tags.iter().keys() {
+            let key = key.unwrap();
+            let (tag, id) = split_tag_key(&key);
+            if !zettels.get(&id).is_some_and(|zettel| zettel.tags.contains(tag)) {
+                problems.push(format!("Tag index has {} for Zettel {}, which doesn't have it", tag, id.0));
+            }
+            indexed_tags.insert((tag.to_string(), id));
+        }
+
+        for entry in self.shares.iter() {
+            let (token, id) = entry.unwrap();
+            let id = ZettelId::decode(id.deref().try_into().unwrap());
+            let token = String::from_utf8_lossy(&token);
+            if zettels.get(&id).and_then(|zettel| zettel.share_token.as_deref()) != Some(&*token) {
+                problems.push(format!("Share token {} is for Zettel {}, which isn't shared with it", token, id.0));
+            }
+        }
+
+        for (id, zettel) in &zettels {
+            for tag in &zettel.tags {
+                if !indexed_tags.contains(&(tag.clone(), *id)) {
+                    problems.push(format!("Zettel {} has tag {}, which is missing from the tag index", id.0, tag));
+                }
+            }
+            if let Some(token) = &zettel.share_token {
+                if self.shares.get(token.as_bytes()).unwrap().as_deref() != Some(&id.encode()[..]) {
+                    problems
+                        .push(format!("Zettel {} is shared, but its token is missing from the share index", id.0));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Bring the Zettels up to date with this version of Commonplace: each one is rewritten in the current format
+    /// (filling in any fields added since it was last saved), and the tag and share indexes are rebuilt from them.
+    /// Nothing is changed if any Zettel can't be read, and the error is returned instead. Returns how many Zettels
+    /// were rewritten.
+    pub fn migrate(&self) -> Result<usize, (ZettelId, DeserializeError)> {
+        let _writing = self.snapshot_lock.write().unwrap();
+        let mut zettels = Vec::new();
+        for entry in self.zettels.iter() {
+            let (key, value) = entry.unwrap();
+            let id = ZettelId::decode(key.deref().try_into().unwrap());
+            zettels.push((id, self.decode(id, &value).map_err(|err| (id, err))?));
+        }
+
+        /*
+         * This is only run from the command line, with exclusive access to the database, so it doesn't need to be
+         * done in one transaction.
+         */
+        self.tags.clear().unwrap();
+        self.shares.clear().unwrap();
+        for (id, zettel) in &mut zettels {
+            zettel.tags = &zettel.explicit_tags | &zettel.content.tags();
+            for tag in &zettel.tags {
+                self.tags.insert(tag_key(tag, *id), &[]).unwrap();
+            }
+            if let Some(token) = &zettel.share_token {
+                self.shares.insert(token.as_bytes(), &id.encode()).unwrap();
+            }
+            self.zettels.insert(id.encode(), self.encode(*id, zettel)).unwrap();
+        }
+        self.meta.insert(TAG_INDEX_KEY, &[]).unwrap();
+        self.flush();
+
+        Ok(zettels.len())
+    }
+}
+
+/// Generate a new random token, for things like share links and sessions. These need to be unguessable, as they
+/// give access to things, so they're made from 128 bits from the OS's random number generator.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn tag_key(tag: &str, id: ZettelId) -> Vec<u8> {
+    let mut key = Vec::from(tag.as_bytes());
+    key.push(0);
+    key.extend(id.encode());
+    key
+}
+
+fn split_tag_key(key: &[u8]) -> (&str, ZettelId) {
+    let (tag, id) = key.split_at(key.len() - 9);
+    (std::str::from_utf8(tag).unwrap(), ZettelId::decode(id[1..].try_into().unwrap()))
+}
+
+fn update_tag_index(
+    tags: &TransactionalTree,
+    id: ZettelId,
+    old: &BTreeSet<String>,
+    new: &BTreeSet<String>,
+) -> Result<(), ConflictableTransactionError> {
+    for removed in old.difference(new) {
+        tags.remove(tag_key(removed, id))?;
+    }
+    for added in new.difference(old) {
+        tags.insert(tag_key(added, id), &[])?;
+    }
+    Ok(())
 }
 
 // TODO: if we just segregate Zettle versions into separate db trees, is this still needed?
@@ -72,35 +583,151 @@ impl ZettelStore {
 ///      if the configuration changes)).
 pub const CURRENT_ZETTEL_FORMAT_VERSION: u16 = 2;
 
+/// Set in a Zettel's version when the rest of its value is encrypted (see `encryption`). The encrypted part is the
+/// nonce followed by the ciphertext. The version and the Zettel's ID are used as associated data, so neither can be
+/// changed without the Zettel failing to decrypt - otherwise, someone with access to the database could swap two
+/// Zettels' contents without knowing the key.
+const ENCRYPTED_FLAG: u16 = 0x8000;
+
+fn is_encrypted(bytes: &[u8]) -> bool {
+    bytes.get(0..2).is_some_and(|header| u16::from_le_bytes(header.try_into().unwrap()) & ENCRYPTED_FLAG != 0)
+}
+
+/// The associated data an encrypted Zettel is encrypted with.
+fn associated_data(header: &[u8], id: ZettelId) -> Vec<u8> {
+    let mut data = Vec::from(header);
+    data.extend(id.encode());
+    data
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ZettelRecord {
     pub title: String,
     pub content: ZettelContent,
     pub backlinks: Vec<ZettelId>,
+    /*
+     * Tags, share tokens, and permissions were added after version 2 of the format was introduced. Missing fields
+     * are defaulted, so older records can still be read without a migration.
+     */
+    /// All of the Zettel's tags - both those set explicitly, and those picked out of its content.
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub explicit_tags: BTreeSet<String>,
+    /// If the Zettel has been shared publicly, the token that it can be viewed with.
+    #[serde(default)]
+    pub share_token: Option<String>,
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+/// Who owns a Zettel, and who else can see it. Zettels from before there were users don't have an owner, and can be
+/// seen and changed by everyone.
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct Permissions {
+    pub owner: Option<String>,
+    /// The workspace the Zettel belongs to, if any.
+    pub workspace: Option<String>,
+    pub visibility: Visibility,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Visibility {
+    /// Only the owner can see the Zettel.
+    Private,
+    /// The owner and the members of the Zettel's workspace can see it.
+    #[default]
+    Workspace,
+    /// Everyone can see the Zettel.
+    Public,
+}
+
+impl Permissions {
+    /// Who can see a Zettel with these permissions, as a list of access keys (see `Access::keys`).
+    pub fn readers(&self) -> Vec<String> {
+        let Some(owner) = &self.owner else {
+            return vec![EVERYONE.to_string()];
+        };
+
+        match (self.visibility, &self.workspace) {
+            (Visibility::Public, _) => vec![EVERYONE.to_string()],
+            (Visibility::Workspace, Some(workspace)) => vec![user_key(owner), workspace_key(workspace)],
+            _ => vec![user_key(owner)],
+        }
+    }
+
+    /// Whether everyone can see a Zettel with these permissions, including people who aren't logged in.
+    pub fn is_public(&self) -> bool {
+        self.readers().iter().any(|reader| reader == EVERYONE)
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum DeserializeError {
     WrongVersion,
+    /// The Zettel is encrypted, and no key was given to decrypt it.
+    Encrypted,
+    /// The Zettel couldn't be decrypted with the given key, either because it was encrypted with a different one or
+    /// because it's been tampered with (including being moved to a different ID).
+    WrongKey,
+    /// The Zettel isn't encrypted, but every Zettel in the database should be.
+    Unencrypted,
+    /// The Zettel's data is too short to have a version, or can't be deserialized.
+    Corrupt,
 }
 
 impl ZettelRecord {
     pub fn new() -> ZettelRecord {
-        ZettelRecord { title: String::new(), content: ZettelContent::empty(), backlinks: Vec::new() }
+        ZettelRecord {
+            title: String::new(),
+            content: ZettelContent::empty(),
+            backlinks: Vec::new(),
+            tags: BTreeSet::new(),
+            explicit_tags: BTreeSet::new(),
+            share_token: None,
+            permissions: Permissions::default(),
+        }
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut bytes = Vec::from(u16::to_le_bytes(CURRENT_ZETTEL_FORMAT_VERSION));
-        bytes.extend(&serde_cbor::to_vec(self).unwrap());
-        bytes
+    /// Serialize the Zettel with the given ID, encrypting it if a `cipher` is given.
+    pub fn serialize(&self, id: ZettelId, cipher: Option<&Cipher>) -> Vec<u8> {
+        let cbor = serde_cbor::to_vec(self).unwrap();
+        match cipher {
+            Some(cipher) => {
+                let header = u16::to_le_bytes(CURRENT_ZETTEL_FORMAT_VERSION | ENCRYPTED_FLAG);
+                let mut bytes = Vec::from(header);
+                bytes.extend(cipher.encrypt(&associated_data(&header, id), &cbor));
+                bytes
+            }
+            None => {
+                let mut bytes = Vec::from(u16::to_le_bytes(CURRENT_ZETTEL_FORMAT_VERSION));
+                bytes.extend(&cbor);
+                bytes
+            }
+        }
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Result<ZettelRecord, DeserializeError> {
-        let format_version = u16::from_le_bytes(bytes[0..2].try_into().unwrap());
-        if format_version != CURRENT_ZETTEL_FORMAT_VERSION {
+    /// Deserialize the Zettel with the given ID. Encrypted Zettels can only be read with the `cipher` they were
+    /// encrypted with, and under the ID they were encrypted for, but unencrypted Zettels can always be read.
+    pub fn deserialize(
+        id: ZettelId,
+        bytes: &[u8],
+        cipher: Option<&Cipher>,
+    ) -> Result<ZettelRecord, DeserializeError> {
+        let Some(header) = bytes.get(0..2) else {
+            return Err(DeserializeError::Corrupt);
+        };
+        let version = u16::from_le_bytes(header.try_into().unwrap());
+        if version & !ENCRYPTED_FLAG != CURRENT_ZETTEL_FORMAT_VERSION {
             return Err(DeserializeError::WrongVersion);
         }
 
-        Ok(serde_cbor::from_slice(&bytes[2..]).unwrap())
+        if version & ENCRYPTED_FLAG == 0 {
+            return serde_cbor::from_slice(&bytes[2..]).map_err(|_| DeserializeError::Corrupt);
+        }
+        let cipher = cipher.ok_or(DeserializeError::Encrypted)?;
+        let cbor = cipher.decrypt(&associated_data(header, id), &bytes[2..]).ok_or(DeserializeError::WrongKey)?;
+        serde_cbor::from_slice(&cbor).map_err(|_| DeserializeError::Corrupt)
     }
 }
@@ -0,0 +1,238 @@
+use crate::{audit::Action, workspace::Access, AppState};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension,
+    Json,
+};
+use commonplace::ZettelId;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    sync::Arc,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ListParams {
+    /// Only list this tag and the tags nested beneath it.
+    prefix: Option<String>,
+}
+
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Vec<TagCount>>, StatusCode> {
+    let prefix = match params.prefix {
+        Some(prefix) => Some(normalize(&prefix).ok_or(StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
+
+    let tags = visible_tag_counts(&state, &access)
+        .into_iter()
+        .filter(|(tag, _)| prefix.as_ref().is_none_or(|prefix| is_within(tag, prefix)))
+        .map(|(tag, count)| TagCount { tag, count })
+        .collect::<Vec<_>>();
+    Ok(Json(tags))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ZettelsParams {
+    /// Also include Zettels with tags nested beneath the given tag.
+    #[serde(default)]
+    subtree: bool,
+}
+
+pub async fn zettels(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(tag): Path<String>,
+    Query(params): Query<ZettelsParams>,
+) -> Result<Json<Vec<ZettelId>>, StatusCode> {
+    let tag = normalize(&tag).ok_or(StatusCode::BAD_REQUEST)?;
+
+    let mut ids = if params.subtree {
+        let mut ids = state.store.tagged_within(&tag).into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+        ids.sort_by_key(|id| id.0);
+        ids.dedup();
+        ids
+    } else {
+        state.store.tagged(&tag)
+    };
+    ids.retain(|id| access.read(state.store.get(*id)).is_ok());
+    Ok(Json(ids))
+}
+
+/// Count the tags on the Zettels a user can see. The tag index covers everyone's Zettels, so counting from it would
+/// leak tags from private Zettels - instead we go through the Zettels themselves.
+fn visible_tag_counts(state: &AppState, access: &Access) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for (_, record) in state.store.visible(access) {
+        for tag in record.tags {
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RenameParams {
+    from: String,
+    to: String,
+}
+
+/// Rename a tag, along with all of the tags nested beneath it. This fails if any of the new tags is already in use -
+/// use `tags.merge` to combine two existing tags.
+pub async fn rename(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Json(params): Json<RenameParams>,
+) -> Result<Json<Vec<ZettelId>>, StatusCode> {
+    let from = normalize(&params.from).ok_or(StatusCode::BAD_REQUEST)?;
+    let to = normalize(&params.to).ok_or(StatusCode::BAD_REQUEST)?;
+    if is_within(&to, &from) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let existing = visible_tag_counts(&state, &access).into_keys().collect::<BTreeSet<_>>();
+    if existing.iter().filter_map(|tag| rename_tag(tag, &from, &to)).any(|renamed| existing.contains(&renamed)) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    Ok(Json(state.blocking(move |state| rewrite(state, &access, &from, &to)).await))
+}
+
+/// Merge one tag (and the tags nested beneath it) into another, which may already exist.
+pub async fn merge(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Json(params): Json<RenameParams>,
+) -> Result<Json<Vec<ZettelId>>, StatusCode> {
+    let from = normalize(&params.from).ok_or(StatusCode::BAD_REQUEST)?;
+    let to = normalize(&params.to).ok_or(StatusCode::BAD_REQUEST)?;
+    if is_within(&to, &from) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(Json(state.blocking(move |state| rewrite(state, &access, &from, &to)).await))
+}
+
+/// Rename a tag on every Zettel the user can change. Other people's Zettels keep their tags.
+fn rewrite(state: &AppState, access: &Access, from: &str, to: &str) -> Vec<ZettelId> {
+    let updated = state.store.rename_tag(from, to, &|record| access.can_write(record));
+    state.index.update_zettels(&updated);
+    for (id, _) in &updated {
+        state.audit.record(&access.username, Action::RenameTag, Some(*id), Some(format!("{} to {}", from, to)));
+    }
+    updated.into_iter().map(|(id, _)| id).collect()
+}
+
+/// Tags are case-insensitive, and can only contain alphanumeric characters, `-`, `_`, and `/`. This is what allows us
+/// to pick them out of running text as `#hashtags`, and lets us use a NUL byte as a separator in the tag index.
+///
+/// Tags can be nested by separating their parts with `/`: e.g. `project/commonplace/design` is nested beneath
+/// `project/commonplace`, which is itself nested beneath `project`.
+pub fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || c == '/'
+}
+
+/// Turn a user-provided tag into its canonical form, or return `None` if it isn't a valid tag. A leading `#` is
+/// accepted, as people will inevitably type one.
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag = tag.trim();
+    let tag = tag.strip_prefix('#').unwrap_or(tag);
+
+    if !tag.chars().all(is_tag_char) || tag.split('/').any(|part| part.is_empty()) {
+        return None;
+    }
+
+    Some(tag.to_lowercase())
+}
+
+/// Whether `tag` is `parent`, or is nested somewhere beneath it.
+pub fn is_within(tag: &str, parent: &str) -> bool {
+    match tag.strip_prefix(parent) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// If `tag` is `from` or is nested beneath it, get its name once `from` is renamed to `to`.
+pub fn rename_tag(tag: &str, from: &str, to: &str) -> Option<String> {
+    if is_within(tag, from) {
+        Some(format!("{}{}", to, &tag[from.len()..]))
+    } else {
+        None
+    }
+}
+
+/// Find all the `#hashtags` in a piece of text. To avoid picking up things like `C#` or URL fragments, the `#`
+/// must start a word, and to avoid picking up issue numbers like `#123`, the tag must contain something other than
+/// digits.
+pub fn hashtags(text: &str) -> Vec<String> {
+    hashtag_spans(text).into_iter().map(|span| text[span].to_lowercase()).collect()
+}
+
+/// Rewrite any `#hashtags` in `text` that would be affected by renaming the tag `from` to `to`. Returns `None` if
+/// the text doesn't need to change.
+pub fn rename_hashtags(text: &str, from: &str, to: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut last = 0;
+
+    for span in hashtag_spans(text) {
+        if let Some(renamed) = rename_tag(&text[span.clone()].to_lowercase(), from, to) {
+            result.push_str(&text[last..span.start]);
+            result.push_str(&renamed);
+            last = span.end;
+        }
+    }
+
+    if last == 0 {
+        return None;
+    }
+    result.push_str(&text[last..]);
+    Some(result)
+}
+
+/// Find the byte ranges of the `#hashtags` in a piece of text, not including the `#`s.
+fn hashtag_spans(text: &str) -> Vec<Range<usize>> {
+    let mut spans = Vec::new();
+    let mut previous = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if c == '#' && !previous.is_some_and(is_tag_char) {
+            previous = Some(c);
+            let start = i + 1;
+            let mut end = start;
+            while let Some(&(j, next)) = chars.peek() {
+                if !is_tag_char(next) {
+                    break;
+                }
+                end = j + next.len_utf8();
+                previous = Some(next);
+                chars.next();
+            }
+
+            /*
+             * A trailing `/` is much more likely to be punctuation than part of the tag, and a nested tag can't
+             * have an empty part anyway.
+             */
+            let tag = text[start..end].trim_end_matches('/');
+            if tag.chars().any(|c| !c.is_ascii_digit()) && !tag.split('/').any(|part| part.is_empty()) {
+                spans.push(start..(start + tag.len()));
+            }
+        } else {
+            previous = Some(c);
+        }
+    }
+
+    spans
+}
@@ -0,0 +1,336 @@
+//! A client for reading and editing Zettels from the terminal. Zettels can be listed and searched, and edited as
+//! Markdown in `$VISUAL` or `$EDITOR` - when the editor is closed, the Markdown is parsed back into a Zettel and
+//! saved.
+//!
+//! Some formatting (like highlight colours, and cells that span several rows of a table) can't be written as
+//! Markdown, so would be lost by editing a Zettel this way. Zettels with formatting like that can only be edited
+//! with `--force`.
+//!
+//! The client either talks to a running server, using a personal API token (`--server` and `--token`, or
+//! `COMMONPLACE_SERVER` and `COMMONPLACE_TOKEN`), or opens the database directly as one of its users (`--user`, or
+//! `COMMONPLACE_USER`), in which case the server can't be running at the same time. Either way, it can only see
+//! and change what that user could through the app.
+
+use crate::{
+    audit::{Action, AuditLog},
+    auth::UserStore,
+    config::Config,
+    index::Index,
+    markdown,
+    store::ZettelStore,
+    workspace::{Access, WorkspaceStore},
+    zettel::{FoundZettel, QueryResult, ZettelContent, ZettelUpdate},
+};
+use axum::http::StatusCode;
+use clap::Args;
+use commonplace::ZettelId;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, ErrorKind, Write},
+    process::Command,
+    sync::Arc,
+};
+
+#[derive(Clone, Debug, Args)]
+pub struct ClientArgs {
+    /// The URL of the server to use, e.g. `http://localhost:8000`
+    #[arg(long, env = "COMMONPLACE_SERVER", conflicts_with = "user")]
+    server: Option<String>,
+    /// A personal API token for the server. This needs the `read-write` scope to edit Zettels
+    #[arg(long, env = "COMMONPLACE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Open the database directly, as this user, instead of using a server
+    #[arg(long, env = "COMMONPLACE_USER")]
+    user: Option<String>,
+}
+
+pub enum Client {
+    Api(Api),
+    Local(Box<Local>),
+}
+
+pub struct Api {
+    http: reqwest::Client,
+    /// The server's URL, without a trailing slash.
+    server: String,
+    token: String,
+}
+
+/// The database, opened directly as one of its users.
+pub struct Local {
+    store: ZettelStore,
+    index: Arc<Index>,
+    audit: AuditLog,
+    access: Access,
+}
+
+impl Client {
+    pub fn open(args: ClientArgs, config: &Config) -> io::Result<Client> {
+        match (args.server, args.token, args.user) {
+            (Some(server), Some(token), _) => Ok(Client::Api(Api {
+                http: reqwest::Client::new(),
+                server: server.trim_end_matches('/').to_string(),
+                token,
+            })),
+            (Some(_), None, _) => Err(io::Error::other("Using a server needs an API token (--token)")),
+            (None, _, Some(username)) => {
+                let store = ZettelStore::new(&config.db);
+                if UserStore::new(&store).get(&username).is_none() {
+                    return Err(io::Error::new(ErrorKind::NotFound, format!("No user called {}", username)));
+                }
+                let access = Access { workspaces: WorkspaceStore::new(&store).memberships(&username), username };
+
+                let index = Index::new(&config.index, config.writer_heap_bytes, store.is_encrypted());
+                if index.needs_rebuild || store.filled_tag_index() {
+                    index.rebuild(store.all());
+                }
+                let audit = AuditLog::new(&store);
+                Ok(Client::Local(Box::new(Local { store, index, audit, access })))
+            }
+            (None, _, None) => Err(io::Error::other("Either --server and --token, or --user, need to be given")),
+        }
+    }
+
+    /// Get the ID and title of every Zettel the user can see.
+    pub async fn list(&self) -> io::Result<Vec<(ZettelId, String)>> {
+        match self {
+            Client::Api(api) => {
+                let zettels = api
+                    .get("/api/zettel.list", None)
+                    .await?
+                    .json::<Vec<QueryResult>>()
+                    .await
+                    .map_err(http_error)?;
+                Ok(zettels.into_iter().map(|zettel| (zettel.id, zettel.title)).collect())
+            }
+            Client::Local(local) => {
+                Ok(local.store.visible(&local.access).into_iter().map(|(id, record)| (id, record.title)).collect())
+            }
+        }
+    }
+
+    pub async fn search(&self, query: &str, tags: &[String]) -> io::Result<Vec<ZettelId>> {
+        match self {
+            Client::Api(api) => {
+                let url =
+                    format!("/api/zettel.search?{}", encode_query(&[("query", query), ("tags", &tags.join(","))]));
+                api.get(&url, None).await?.json().await.map_err(http_error)
+            }
+            Client::Local(local) => {
+                let tags = tags
+                    .iter()
+                    .map(|tag| {
+                        crate::tags::normalize(tag).ok_or_else(|| {
+                            io::Error::new(ErrorKind::InvalidInput, format!("Invalid tag: {}", tag))
+                        })
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                Ok(local.index.search(query, &tags, &local.access))
+            }
+        }
+    }
+
+    pub async fn get(&self, id: ZettelId) -> io::Result<FoundZettel> {
+        match self {
+            Client::Api(api) => {
+                api.get(&format!("/api/zettel.fetch/{}", id.0), Some(id)).await?.json().await.map_err(http_error)
+            }
+            Client::Local(local) => {
+                let record =
+                    local.access.read(local.store.get(id)).map_err(|status| status_error(status, Some(id)))?;
+                Ok(FoundZettel {
+                    title: record.title,
+                    content: record.content,
+                    tags: record.tags,
+                    permissions: record.permissions,
+                })
+            }
+        }
+    }
+
+    /// Get a Zettel as Markdown, with links to other Zettels as wiki-style links.
+    pub async fn markdown(&self, id: ZettelId) -> io::Result<String> {
+        match self {
+            Client::Api(api) => {
+                api.get(&format!("/api/zettel.export/{}", id.0), Some(id)).await?.text().await.map_err(http_error)
+            }
+            Client::Local(local) => {
+                let Local { store, access, .. } = &**local;
+                let record = access.read(store.get(id)).map_err(|status| status_error(status, Some(id)))?;
+                let title = |target| access.read(store.get(target)).ok().map(|record| record.title);
+                let link = |target| markdown::wiki_link(target, title(target).as_deref());
+                Ok(markdown::render(&record.title, &record.content, &link))
+            }
+        }
+    }
+
+    pub async fn save(&self, id: ZettelId, update: ZettelUpdate) -> io::Result<()> {
+        match self {
+            Client::Api(api) => {
+                let response = api
+                    .http
+                    .post(format!("{}/api/zettel.update/{}", api.server, id.0))
+                    .bearer_auth(&api.token)
+                    .json(&update)
+                    .send()
+                    .await
+                    .map_err(http_error)?;
+                check_status(response.status(), Some(id))
+            }
+            Client::Local(local) => {
+                let Local { store, index, audit, access } = &**local;
+                access.write(store.get(id)).map_err(|status| status_error(status, Some(id)))?;
+                store.update(id, update);
+                index.update_zettels(&[(id, store.get(id).unwrap())]);
+                audit.record(&access.username, Action::UpdateZettel, Some(id), None);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Api {
+    /// Make a `GET` request to the API. If it's for a particular Zettel, `zettel` is used to say which wasn't found.
+    async fn get(&self, path: &str, zettel: Option<ZettelId>) -> io::Result<reqwest::Response> {
+        let response = self
+            .http
+            .get(format!("{}{}", self.server, path))
+            .bearer_auth(&self.token)
+            .send()
+            .await
+            .map_err(http_error)?;
+        check_status(response.status(), zettel)?;
+        Ok(response)
+    }
+}
+
+pub async fn list(client: &Client) -> io::Result<()> {
+    for (id, title) in client.list().await? {
+        print_zettel(id, &title);
+    }
+    Ok(())
+}
+
+pub async fn search(client: &Client, query: &str, tags: &[String]) -> io::Result<()> {
+    let found = client.search(query, tags).await?;
+    let titles = client.list().await?.into_iter().collect::<HashMap<_, _>>();
+    for id in found {
+        print_zettel(id, titles.get(&id).map_or("", String::as_str));
+    }
+    Ok(())
+}
+
+/// Open a Zettel in the user's editor, and save it if it's been changed when the editor is closed. Unless `force`
+/// is set, Zettels that can't be turned into Markdown and back without losing something aren't opened.
+pub async fn edit(client: &Client, id: ZettelId, force: bool) -> io::Result<()> {
+    let stored = client.get(id).await?;
+    let original = client.markdown(id).await?;
+    let zettels = client.list().await?;
+    let resolve = markdown::resolver(&zettels);
+
+    let round_trip = markdown::parse(&original, &resolve);
+    if !force && !same_zettel(&stored, round_trip.title.as_deref().unwrap_or_default(), &round_trip.content) {
+        return Err(io::Error::other(format!(
+            "Zettel {} has formatting that can't be written as Markdown, so editing it here would lose it. Use \
+             --force to edit it anyway.",
+            id.0
+        )));
+    }
+
+    /*
+     * The Markdown may be decrypted text that should never be readable by anyone else, so it's written to a new
+     * file that only we can read, in a new directory that only we can read (which is also where editors put swap
+     * files). Both are removed when they're dropped, however this returns.
+     */
+    let mut dir = tempfile::Builder::new();
+    dir.prefix("commonplace-");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        dir.permissions(fs::Permissions::from_mode(0o700));
+    }
+    let dir = dir.tempdir()?;
+    let mut file = tempfile::Builder::new().prefix(&format!("{}-", id.0)).suffix(".md").tempfile_in(&dir)?;
+    file.write_all(original.as_bytes())?;
+    file.flush()?;
+
+    /*
+     * Editors like `code --wait` need arguments, so the variable is split on whitespace rather than being treated as
+     * the path to a single program.
+     */
+    let editor = std::env::var("VISUAL").or_else(|_| std::env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let status = Command::new(words.next().unwrap_or("vi")).args(words).arg(file.path()).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("Editor exited with {}, so not saving", status)));
+    }
+
+    let edited = fs::read_to_string(file.path())?;
+    if edited == original {
+        println!("No changes to {}", id.0);
+        return Ok(());
+    }
+
+    /*
+     * If the Zettel can't be saved, the edited Markdown is printed so the changes aren't lost - it can't be left in
+     * the file, as that's about to be removed.
+     */
+    let unsaved = |err: io::Error| {
+        println!("{}", edited);
+        io::Error::new(err.kind(), format!("{} (your changes are above)", err))
+    };
+
+    let current = client.get(id).await.map_err(unsaved)?;
+    if !same_zettel(&current, &stored.title, &stored.content) {
+        return Err(unsaved(io::Error::other(format!(
+            "Zettel {} was changed while you were editing it, so not saving",
+            id.0
+        ))));
+    }
+
+    let parsed = markdown::parse(&edited, &resolve);
+    let update = ZettelUpdate { title: parsed.title.unwrap_or_default(), content: parsed.content, tags: None };
+    client.save(id, update).await.map_err(unsaved)?;
+    println!("Saved {}", id.0);
+    Ok(())
+}
+
+/// Whether a Zettel has the given title and content.
+fn same_zettel(zettel: &FoundZettel, title: &str, content: &ZettelContent) -> bool {
+    zettel.title == title
+        && serde_json::to_value(&zettel.content).unwrap() == serde_json::to_value(content).unwrap()
+}
+
+fn print_zettel(id: ZettelId, title: &str) {
+    println!("{}  {}", id.0, if title.is_empty() { "(untitled)" } else { title });
+}
+
+fn encode_query(params: &[(&str, &str)]) -> String {
+    reqwest::Url::parse_with_params("http://localhost/", params).unwrap().query().unwrap_or("").to_string()
+}
+
+fn check_status(status: StatusCode, zettel: Option<ZettelId>) -> io::Result<()> {
+    if status.is_success() {
+        Ok(())
+    } else {
+        Err(status_error(status, zettel))
+    }
+}
+
+fn status_error(status: StatusCode, zettel: Option<ZettelId>) -> io::Error {
+    match (status, zettel) {
+        (StatusCode::NOT_FOUND, Some(id)) => {
+            io::Error::new(ErrorKind::NotFound, format!("Zettel {} not found", id.0))
+        }
+        (StatusCode::UNAUTHORIZED, _) => io::Error::new(ErrorKind::PermissionDenied, "The API token isn't valid"),
+        (StatusCode::FORBIDDEN, _) => {
+            io::Error::new(ErrorKind::PermissionDenied, "Not allowed to change that Zettel")
+        }
+        (status, _) => io::Error::other(format!("The server returned {}", status)),
+    }
+}
+
+fn http_error(err: reqwest::Error) -> io::Error {
+    io::Error::other(format!("Couldn't reach the server: {}", err))
+}
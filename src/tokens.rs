@@ -0,0 +1,102 @@
+//! Personal API tokens, for scripts and integrations that can't log in with a password. Tokens belong to a user,
+//! and can be limited to a `Scope`. They're only shown once, when they're created.
+
+use crate::{
+    audit::Action,
+    auth::{ApiTokenRecord, CurrentUser, Scope},
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ApiToken {
+    pub id: String,
+    pub name: String,
+    pub scope: Scope,
+    pub created: DateTime<Utc>,
+}
+
+impl From<ApiTokenRecord> for ApiToken {
+    fn from(record: ApiTokenRecord) -> ApiToken {
+        ApiToken { id: record.id, name: record.name, scope: record.scope, created: record.created }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CreatedToken {
+    /// The token itself. This is the only time it's available.
+    pub token: String,
+    #[serde(flatten)]
+    pub details: ApiToken,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewToken {
+    name: String,
+    scope: Scope,
+}
+
+/// Managing tokens needs the `Admin` scope, so a token can't be used to create a more powerful one.
+fn check_scope(user: &CurrentUser) -> Result<(), StatusCode> {
+    if user.scope == Scope::Admin {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Json(new_token): Json<NewToken>,
+) -> Result<Json<CreatedToken>, StatusCode> {
+    check_scope(&user)?;
+    if new_token.name.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let (token, record) = state
+        .blocking(move |state| {
+            let (token, record) =
+                state.users.create_api_token(&user.username, new_token.name.trim(), new_token.scope);
+            state.audit.record(&user.username, Action::CreateToken, None, Some(record.id.clone()));
+            (token, record)
+        })
+        .await;
+    Ok(Json(CreatedToken { token, details: record.into() }))
+}
+
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+) -> Result<Json<Vec<ApiToken>>, StatusCode> {
+    check_scope(&user)?;
+    let mut tokens = state.users.api_tokens(&user.username).into_iter().map(ApiToken::from).collect::<Vec<_>>();
+    tokens.sort_by_key(|token| token.created);
+    Ok(Json(tokens))
+}
+
+pub async fn revoke(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Path(id): Path<String>,
+) -> Result<(), StatusCode> {
+    check_scope(&user)?;
+    state
+        .blocking(move |state| {
+            if !state.users.revoke_api_token(&user.username, &id) {
+                return Err(StatusCode::NOT_FOUND);
+            }
+            state.audit.record(&user.username, Action::RevokeToken, None, Some(id));
+            Ok(())
+        })
+        .await
+}
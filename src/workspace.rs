@@ -0,0 +1,293 @@
+//! Workspaces, which are groups of users that can see each other's Zettels. Every Zettel has an owner, and can be
+//! private to them, visible to the members of its workspace, or visible to everyone (see `store::Permissions`).
+//!
+//! Anything that shows Zettels to a user needs to go through an `Access`, which describes what they can see, so
+//! that private Zettels never leak through lists, search results, links, or the graph.
+
+use crate::{
+    audit::Action,
+    auth::CurrentUser,
+    store::{Permissions, Visibility, ZettelRecord, ZettelStore},
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use commonplace::ZettelId;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, sync::Arc};
+
+/// The access key for Zettels that everyone can see.
+pub const EVERYONE: &str = "*";
+
+pub fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+pub fn workspace_key(workspace: &str) -> String {
+    format!("workspace:{}", workspace)
+}
+
+pub struct WorkspaceStore {
+    /// Workspaces, keyed by their name.
+    workspaces: sled::Tree,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WorkspaceRecord {
+    pub members: BTreeSet<String>,
+    pub created: DateTime<Utc>,
+}
+
+impl WorkspaceStore {
+    pub fn new(store: &ZettelStore) -> WorkspaceStore {
+        WorkspaceStore { workspaces: store.tree("workspaces") }
+    }
+
+    pub fn get(&self, name: &str) -> Option<WorkspaceRecord> {
+        self.workspaces.get(name.as_bytes()).unwrap().map(|bytes| serde_cbor::from_slice(&bytes).unwrap())
+    }
+
+    /// Create a new workspace, with a single member. Returns `false` if there's already a workspace with that name.
+    pub fn create(&self, name: &str, member: &str) -> bool {
+        let record = WorkspaceRecord { members: BTreeSet::from([member.to_string()]), created: Utc::now() };
+        self.workspaces
+            .compare_and_swap(name.as_bytes(), None::<&[u8]>, Some(serde_cbor::to_vec(&record).unwrap()))
+            .unwrap()
+            .is_ok()
+    }
+
+    /// Change a workspace's members. Returns `false` if the workspace doesn't exist.
+    pub fn update_members(&self, name: &str, update: impl Fn(&mut BTreeSet<String>)) -> bool {
+        self.workspaces
+            .fetch_and_update(name.as_bytes(), |bytes| {
+                let mut record: WorkspaceRecord = serde_cbor::from_slice(bytes?).unwrap();
+                update(&mut record.members);
+                Some(serde_cbor::to_vec(&record).unwrap())
+            })
+            .unwrap()
+            .is_some()
+    }
+
+    /// Get the names of all the workspaces a user is a member of.
+    pub fn memberships(&self, username: &str) -> BTreeSet<String> {
+        self.workspaces
+            .iter()
+            .filter_map(|entry| {
+                let (name, bytes) = entry.unwrap();
+                let record: WorkspaceRecord = serde_cbor::from_slice(&bytes).unwrap();
+                record.members.contains(username).then(|| String::from_utf8_lossy(&name).into_owned())
+            })
+            .collect()
+    }
+
+    pub fn access(&self, user: &CurrentUser) -> Access {
+        Access { username: user.username.clone(), workspaces: self.memberships(&user.username) }
+    }
+}
+
+/// What a user can see and change. This is worked out once per request, by `auth::require_user`.
+#[derive(Clone, Debug)]
+pub struct Access {
+    pub username: String,
+    pub workspaces: BTreeSet<String>,
+}
+
+impl Access {
+    /// The access keys this user has. A Zettel can be seen if any of these are in its `Permissions::readers`.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = vec![EVERYONE.to_string(), user_key(&self.username)];
+        keys.extend(self.workspaces.iter().map(|workspace| workspace_key(workspace)));
+        keys
+    }
+
+    pub fn can_read(&self, record: &ZettelRecord) -> bool {
+        let keys = self.keys();
+        record.permissions.readers().iter().any(|reader| keys.contains(reader))
+    }
+
+    /// Whether this user can change a Zettel. The owner always can, and so can the members of its workspace,
+    /// unless it's private.
+    pub fn can_write(&self, record: &ZettelRecord) -> bool {
+        let permissions = &record.permissions;
+        match &permissions.owner {
+            None => true,
+            Some(owner) if *owner == self.username => true,
+            Some(_) => {
+                permissions.visibility != Visibility::Private
+                    && permissions.workspace.as_ref().is_some_and(|workspace| self.workspaces.contains(workspace))
+            }
+        }
+    }
+
+    /// Check that this user can see a Zettel, returning it if they can. Zettels they can't see are reported as not
+    /// existing, so their IDs can't be probed.
+    pub fn read(&self, record: Option<ZettelRecord>) -> Result<ZettelRecord, StatusCode> {
+        record.filter(|record| self.can_read(record)).ok_or(StatusCode::NOT_FOUND)
+    }
+
+    /// Check that this user can change a Zettel, returning it if they can.
+    pub fn write(&self, record: Option<ZettelRecord>) -> Result<ZettelRecord, StatusCode> {
+        let record = self.read(record)?;
+        if self.can_write(&record) {
+            Ok(record)
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Workspace {
+    pub name: String,
+    pub members: BTreeSet<String>,
+}
+
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<Vec<Workspace>>, StatusCode> {
+    let workspaces = access
+        .workspaces
+        .iter()
+        .filter_map(|name| Some(Workspace { name: name.clone(), members: state.workspaces.get(name)?.members }))
+        .collect();
+    Ok(Json(workspaces))
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NewWorkspace {
+    name: String,
+}
+
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    Extension(user): Extension<CurrentUser>,
+    Json(workspace): Json<NewWorkspace>,
+) -> Result<Json<Workspace>, StatusCode> {
+    let valid = !workspace.name.is_empty()
+        && workspace.name.len() <= 64
+        && workspace.name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    state
+        .blocking(move |state| {
+            if !state.workspaces.create(&workspace.name, &user.username) {
+                return Err(StatusCode::CONFLICT);
+            }
+            state.audit.record(&user.username, Action::CreateWorkspace, None, Some(workspace.name.clone()));
+            Ok(Json(Workspace { name: workspace.name, members: BTreeSet::from([user.username]) }))
+        })
+        .await
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MemberParams {
+    username: String,
+}
+
+/// Add a user to a workspace. Any member of a workspace can add other users to it.
+pub async fn add_member(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(name): Path<String>,
+    Json(params): Json<MemberParams>,
+) -> Result<(), StatusCode> {
+    if !access.workspaces.contains(&name) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if state.users.get(&params.username).is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    state
+        .blocking(move |state| {
+            state.workspaces.update_members(&name, |members| {
+                members.insert(params.username.clone());
+            });
+            let detail = format!("{} to {}", params.username, name);
+            state.audit.record(&access.username, Action::AddMember, None, Some(detail));
+        })
+        .await;
+    Ok(())
+}
+
+/// Remove a user from a workspace. Any member of a workspace can remove other members, or leave it themselves.
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(name): Path<String>,
+    Json(params): Json<MemberParams>,
+) -> Result<(), StatusCode> {
+    if !access.workspaces.contains(&name) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    state
+        .blocking(move |state| {
+            state.workspaces.update_members(&name, |members| {
+                members.remove(&params.username);
+            });
+            let detail = format!("{} from {}", params.username, name);
+            state.audit.record(&access.username, Action::RemoveMember, None, Some(detail));
+        })
+        .await;
+    Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct PermissionsParams {
+    workspace: Option<String>,
+    visibility: Visibility,
+}
+
+/// Change which workspace a Zettel belongs to, and who can see it. Only the owner can do this, except for Zettels
+/// from before there were users, which are claimed by the first person to change them.
+pub async fn set_permissions(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(id): Path<ZettelId>,
+    Json(params): Json<PermissionsParams>,
+) -> Result<Json<Permissions>, StatusCode> {
+    let record = access.read(state.store.get(id))?;
+    if record.permissions.owner.as_ref().is_some_and(|owner| *owner != access.username) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if let Some(workspace) = &params.workspace {
+        if !access.workspaces.contains(workspace) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    } else if params.visibility == Visibility::Workspace {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let permissions = Permissions {
+        owner: Some(access.username.clone()),
+        workspace: params.workspace,
+        visibility: params.visibility,
+    };
+    let detail = match &permissions.workspace {
+        Some(workspace) => format!("{:?} in {}", permissions.visibility, workspace),
+        None => format!("{:?}", permissions.visibility),
+    };
+    let new = permissions.clone();
+    state
+        .blocking(move |state| {
+            state.store.set_permissions(id, new);
+            /*
+             * Search results are filtered by who can see each Zettel, so the index has to be committed straight away,
+             * rather than carrying on showing the Zettel to people who can't see it any more.
+             */
+            state.index.update_zettels(&[(id, state.store.get(id).unwrap())]);
+            state.audit.record(&access.username, Action::SetPermissions, Some(id), Some(detail));
+        })
+        .await;
+    Ok(Json(permissions))
+}
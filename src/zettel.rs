@@ -1,12 +1,21 @@
-use crate::AppState;
+use crate::{
+    audit::Action,
+    html,
+    markdown,
+    store::{Permissions, Visibility},
+    tags,
+    workspace::Access,
+    AppState,
+};
 use axum::{
     extract::{Path, Query, State},
-    http::StatusCode,
+    http::{header, StatusCode},
+    Extension,
     Json,
 };
 use commonplace::ZettelId;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{collections::BTreeSet, sync::Arc};
 use tracing::error;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,57 +23,125 @@ pub struct QueryResult {
     pub id: ZettelId,
     pub title: String,
     pub content: ZettelContent,
+    pub tags: BTreeSet<String>,
 }
 
-pub async fn create(State(state): State<Arc<AppState>>) -> Result<Json<ZettelId>, StatusCode> {
-    match state.store.create() {
-        Some(id) => Ok(Json(id)),
-        None => Err(StatusCode::TOO_MANY_REQUESTS),
-    }
+#[derive(Clone, Debug, Deserialize)]
+pub struct CreateParams {
+    /// The workspace to create the Zettel in. If this is given, the Zettel is visible to the workspace by default,
+    /// and otherwise it's private.
+    workspace: Option<String>,
+    visibility: Option<Visibility>,
+}
+
+pub async fn create(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Query(params): Query<CreateParams>,
+) -> Result<Json<ZettelId>, StatusCode> {
+    let permissions = permissions_for_new(&access, params)?;
+    let id = state
+        .blocking(move |state| {
+            let id = state.store.create(permissions)?;
+            state.audit.record(&access.username, Action::CreateZettel, Some(id), None);
+            Some(id)
+        })
+        .await
+        .ok_or(StatusCode::TOO_MANY_REQUESTS)?;
+    Ok(Json(id))
+}
+
+/// Work out the permissions for a new Zettel, checking that the user is a member of the workspace it'll be in.
+fn permissions_for_new(access: &Access, params: CreateParams) -> Result<Permissions, StatusCode> {
+    let visibility = match (&params.workspace, params.visibility) {
+        (Some(workspace), _) if !access.workspaces.contains(workspace) => return Err(StatusCode::BAD_REQUEST),
+        (None, Some(Visibility::Workspace)) => return Err(StatusCode::BAD_REQUEST),
+        (_, Some(visibility)) => visibility,
+        (Some(_), None) => Visibility::Workspace,
+        (None, None) => Visibility::Private,
+    };
+    Ok(Permissions { owner: Some(access.username.clone()), workspace: params.workspace, visibility })
 }
 
 pub async fn fetch(
     State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
     Path(id): Path<ZettelId>,
 ) -> Result<Json<FoundZettel>, StatusCode> {
-    match state.store.get(id) {
-        Some(record) => Ok(Json(FoundZettel { title: record.title, content: record.content })),
-        None => Err(StatusCode::NOT_FOUND),
-    }
+    let record = access.read(state.store.get(id))?;
+    Ok(Json(FoundZettel {
+        title: record.title,
+        content: record.content,
+        tags: record.tags,
+        permissions: record.permissions,
+    }))
 }
 
-pub async fn list(State(state): State<Arc<AppState>>) -> Result<Json<Vec<QueryResult>>, StatusCode> {
+pub async fn list(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+) -> Result<Json<Vec<QueryResult>>, StatusCode> {
     let all = state
         .store
-        .all()
+        .visible(&access)
         .into_iter()
-        .map(|(id, record)| QueryResult { id, title: record.title, content: record.content })
+        .map(|(id, record)| QueryResult { id, title: record.title, content: record.content, tags: record.tags })
         .collect();
     Ok(Json(all))
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct SearchParams {
+    #[serde(default)]
     query: String,
+    /// A comma-separated list of tags that results must have.
+    tags: Option<String>,
 }
 
 pub async fn search(
     State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
     Query(params): Query<SearchParams>,
 ) -> Result<Json<Vec<ZettelId>>, StatusCode> {
-    let result = state.index.search(&params.query);
+    let tags = match params.tags {
+        Some(tags) => tags
+            .split(',')
+            .filter(|tag| !tag.trim().is_empty())
+            .map(|tag| tags::normalize(tag).ok_or(StatusCode::BAD_REQUEST))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+
+    let result = state.index.search(&params.query, &tags, &access);
     Ok(Json(result))
 }
 
 pub async fn update(
     State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
     Path(id): Path<ZettelId>,
     update: String,
 ) -> Result<(), StatusCode> {
-    match serde_json::from_str(&update) {
-        Ok(update) => {
-            state.store.update(id, update);
-            state.index.update_zettel(id, &state.store.get(id).unwrap());
+    access.write(state.store.get(id))?;
+
+    match serde_json::from_str::<ZettelUpdate>(&update) {
+        Ok(mut update) => {
+            if let Some(explicit_tags) = update.tags {
+                update.tags = Some(
+                    explicit_tags
+                        .iter()
+                        .map(|tag| tags::normalize(tag).ok_or(StatusCode::BAD_REQUEST))
+                        .collect::<Result<_, _>>()?,
+                );
+            }
+
+            state
+                .blocking(move |state| {
+                    state.store.update(id, update);
+                    state.index.update_zettel(id, &state.store.get(id).unwrap());
+                    state.audit.record(&access.username, Action::UpdateZettel, Some(id), None);
+                })
+                .await;
         }
         Err(err) => error!("Error parsing Zettel update: {:?}", err),
     }
@@ -72,16 +149,79 @@ pub async fn update(
     Ok(())
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExportParams {
+    /// The format to export the Zettel in: either `markdown` (the default), or `html` for a standalone HTML page.
+    format: Option<String>,
+}
+
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    Path(id): Path<ZettelId>,
+    Query(params): Query<ExportParams>,
+) -> Result<([(header::HeaderName, &'static str); 1], String), StatusCode> {
+    let record = access.read(state.store.get(id))?;
+    let title = |target| access.read(state.store.get(target)).ok().map(|record| record.title);
+
+    match params.format.as_deref().unwrap_or("markdown") {
+        "markdown" => {
+            let link = |target| markdown::wiki_link(target, title(target).as_deref());
+            let rendered = markdown::render(&record.title, &record.content, &link);
+            Ok(([(header::CONTENT_TYPE, "text/markdown; charset=utf-8")], rendered))
+        }
+        "html" => {
+            let link = |target| html::zettel_link(target, title(target).as_deref());
+            let rendered = html::page(&record.title, &html::render(&record.content, &link));
+            Ok(([(header::CONTENT_TYPE, "text/html; charset=utf-8")], rendered))
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Create a new Zettel from a Markdown document. If the document starts with a top-level heading, it's used as the
+/// Zettel's title. Wiki-style links are resolved to other Zettels by ID, or by title if there's no Zettel with that
+/// ID. The new Zettel is private to the user importing it.
+pub async fn import(
+    State(state): State<Arc<AppState>>,
+    Extension(access): Extension<Access>,
+    body: String,
+) -> Result<Json<ZettelId>, StatusCode> {
+    let zettels =
+        state.store.visible(&access).into_iter().map(|(id, record)| (id, record.title)).collect::<Vec<_>>();
+    let parsed = markdown::parse(&body, &markdown::resolver(&zettels));
+
+    let permissions = permissions_for_new(&access, CreateParams { workspace: None, visibility: None })?;
+    let update = ZettelUpdate { title: parsed.title.unwrap_or_default(), content: parsed.content, tags: None };
+    let id = state
+        .blocking(move |state| {
+            let id = state.store.create(permissions)?;
+            state.store.update(id, update);
+            state.index.update_zettel(id, &state.store.get(id).unwrap());
+            state.audit.record(&access.username, Action::ImportZettel, Some(id), None);
+            Some(id)
+        })
+        .await
+        .ok_or(StatusCode::TOO_MANY_REQUESTS)?;
+    Ok(Json(id))
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FoundZettel {
     pub title: String,
     pub content: ZettelContent,
+    pub tags: BTreeSet<String>,
+    pub permissions: Permissions,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ZettelUpdate {
     pub title: String,
     pub content: ZettelContent,
+    /// Tags to set explicitly on the Zettel, on top of any `#hashtags` in its content. If this is missing, the
+    /// Zettel's existing explicit tags are kept.
+    #[serde(default)]
+    pub tags: Option<BTreeSet<String>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -108,10 +248,94 @@ impl ZettelContent {
 
         result
     }
+
+    /// Collect the `#hashtags` used in the text of this Zettel. Text in code blocks, or marked as code or a link,
+    /// is ignored.
+    pub fn tags(&self) -> BTreeSet<String> {
+        let mut tags = BTreeSet::new();
+
+        match self {
+            Self::Doc { content } => {
+                for block in content {
+                    block.for_each_inline(&mut |parent, inline| {
+                        if matches!(parent, Block::CodeBlock { .. }) {
+                            return;
+                        }
+
+                        if let Inline::Text { text, marks } = inline {
+                            let skip =
+                                marks.iter().flatten().any(|mark| matches!(mark, Mark::Code | Mark::Link { .. }));
+                            if !skip {
+                                tags.extend(tags::hashtags(text));
+                            }
+                        }
+                    });
+                }
+            }
+        }
+
+        tags
+    }
+
+    /// Get the targets of all the links from this Zettel to other Zettels, in the order they appear. A target
+    /// appears once for each link to it.
+    pub fn links(&self) -> Vec<ZettelId> {
+        let mut links = Vec::new();
+
+        match self {
+            Self::Doc { content } => {
+                for block in content {
+                    block.for_each_inline(&mut |_, inline| {
+                        if let Inline::ZettelLink { attrs } = inline {
+                            links.push(ZettelId(attrs.target));
+                        }
+                    });
+                }
+            }
+        }
+
+        links
+    }
+
+    /// Rewrite any `#hashtags` in this Zettel's text that are affected by renaming the tag `from` to `to`.
+    pub fn rename_tag(&mut self, from: &str, to: &str) {
+        fn rename_in_block(block: &mut Block, from: &str, to: &str) {
+            if let Block::CodeBlock { .. } = block {
+                return;
+            }
+
+            if let Some(inlines) = block.inlines_mut() {
+                for inline in inlines {
+                    if let Inline::Text { text, marks } = inline {
+                        let skip =
+                            marks.iter().flatten().any(|mark| matches!(mark, Mark::Code | Mark::Link { .. }));
+                        if let (false, Some(renamed)) = (skip, tags::rename_hashtags(text, from, to)) {
+                            *text = renamed;
+                        }
+                    }
+                }
+            }
+
+            if let Some(blocks) = block.blocks_mut() {
+                for block in blocks {
+                    rename_in_block(block, from, to);
+                }
+            }
+        }
+
+        match self {
+            Self::Doc { content } => {
+                for block in content {
+                    rename_in_block(block, from, to);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
+#[allow(clippy::enum_variant_names)]
 pub enum Block {
     Paragraph { content: Option<Vec<Inline>> },
     Blockquote { content: Option<Vec<Block>> },
@@ -134,6 +358,80 @@ pub enum Block {
 }
 
 impl Block {
+    /// The child blocks of this block, if it is a block that contains other blocks.
+    pub fn blocks(&self) -> Option<&[Block]> {
+        match self {
+            Block::Blockquote { content }
+            | Block::BulletList { content }
+            | Block::ListItem { content }
+            | Block::OrderedList { content }
+            | Block::TaskList { content }
+            | Block::TaskItem { content, .. }
+            | Block::Table { content }
+            | Block::TableRow { content }
+            | Block::TableHeader { content, .. }
+            | Block::TableCell { content, .. }
+            | Block::Details { content, .. }
+            | Block::DetailsContent { content } => content.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn blocks_mut(&mut self) -> Option<&mut Vec<Block>> {
+        match self {
+            Block::Blockquote { content }
+            | Block::BulletList { content }
+            | Block::ListItem { content }
+            | Block::OrderedList { content }
+            | Block::TaskList { content }
+            | Block::TaskItem { content, .. }
+            | Block::Table { content }
+            | Block::TableRow { content }
+            | Block::TableHeader { content, .. }
+            | Block::TableCell { content, .. }
+            | Block::Details { content, .. }
+            | Block::DetailsContent { content } => content.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// The inline content of this block, if it is a block that directly contains text.
+    pub fn inlines(&self) -> Option<&[Inline]> {
+        match self {
+            Block::Paragraph { content }
+            | Block::CodeBlock { content, .. }
+            | Block::Heading { content, .. }
+            | Block::DetailsSummary { content } => content.as_deref(),
+            _ => None,
+        }
+    }
+
+    pub fn inlines_mut(&mut self) -> Option<&mut Vec<Inline>> {
+        match self {
+            Block::Paragraph { content }
+            | Block::CodeBlock { content, .. }
+            | Block::Heading { content, .. }
+            | Block::DetailsSummary { content } => content.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Call `f` on every inline node in this block and its children, along with the block that directly contains
+    /// it.
+    pub fn for_each_inline<'a>(&'a self, f: &mut impl FnMut(&'a Block, &'a Inline)) {
+        if let Some(inlines) = self.inlines() {
+            for inline in inlines {
+                f(self, inline);
+            }
+        }
+
+        if let Some(blocks) = self.blocks() {
+            for block in blocks {
+                block.for_each_inline(f);
+            }
+        }
+    }
+
     fn append_indexed(&self, s: &mut String) {
         match self {
             Block::Paragraph { content } => {
@@ -266,7 +564,7 @@ pub struct HeadingAttrs {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TaskItemAttrs {
-    checked: bool,
+    pub checked: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -278,9 +576,9 @@ pub struct TableAttrs {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ImageAttrs {
-    src: String,
-    alt: Option<String>,
-    title: Option<String>,
+    pub src: String,
+    pub alt: Option<String>,
+    pub title: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -312,7 +610,7 @@ pub struct ZettelLinkAttrs {
     pub target: u64,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub enum Mark {
     Bold,
@@ -325,7 +623,7 @@ pub enum Mark {
     Highlight { color: Option<String> },
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub struct LinkAttrs {
     pub href: String,
     pub target: String,